@@ -0,0 +1,126 @@
+// Wire format for candle responses: a big-endian u32 holding the number of
+// buckets, then one CANDLE_BYTES record per bucket in time order.
+// Each record is open, high, low and close as i32, then count as u32.
+// Buckets without any prices are all zeros.
+
+pub const CANDLE_BYTES: usize = 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Candle {
+    pub open: i32,
+    pub high: i32,
+    pub low: i32,
+    pub close: i32,
+    pub count: u32,
+}
+
+pub fn encode_candles(candles: &[Candle]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + candles.len() * CANDLE_BYTES);
+    bytes.extend_from_slice(&(candles.len() as u32).to_be_bytes());
+
+    for candle in candles {
+        bytes.extend_from_slice(&candle.open.to_be_bytes());
+        bytes.extend_from_slice(&candle.high.to_be_bytes());
+        bytes.extend_from_slice(&candle.low.to_be_bytes());
+        bytes.extend_from_slice(&candle.close.to_be_bytes());
+        bytes.extend_from_slice(&candle.count.to_be_bytes());
+    }
+
+    bytes
+}
+
+// Clients reading candle responses need the other half of the format. The
+// server only ever encodes, so it's only built for the tests.
+#[cfg(test)]
+pub fn decode_candles(bytes: &[u8]) -> Option<Vec<Candle>> {
+    let (length, mut records) = bytes.split_first_chunk::<4>()?;
+    let length = u32::from_be_bytes(*length) as usize;
+    if records.len() != length.checked_mul(CANDLE_BYTES)? {
+        return None;
+    }
+
+    let mut candles = Vec::with_capacity(length);
+    while let Some((record, rest)) = records.split_first_chunk::<CANDLE_BYTES>() {
+        let field = |offset: usize| {
+            [
+                record[offset],
+                record[offset + 1],
+                record[offset + 2],
+                record[offset + 3],
+            ]
+        };
+        candles.push(Candle {
+            open: i32::from_be_bytes(field(0)),
+            high: i32::from_be_bytes(field(4)),
+            low: i32::from_be_bytes(field(8)),
+            close: i32::from_be_bytes(field(12)),
+            count: u32::from_be_bytes(field(16)),
+        });
+        records = rest;
+    }
+
+    Some(candles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candle(open: i32, high: i32, low: i32, close: i32, count: u32) -> Candle {
+        Candle {
+            open,
+            high,
+            low,
+            close,
+            count,
+        }
+    }
+
+    #[test]
+    fn candles_round_trip() {
+        let candles = [
+            candle(10, 30, 5, 20, 4),
+            candle(i32::MIN, i32::MAX, i32::MIN, i32::MAX, u32::MAX),
+            candle(-1, -1, -1, -1, 1),
+        ];
+        let bytes = encode_candles(&candles);
+        assert_eq!(bytes.len(), 4 + candles.len() * CANDLE_BYTES);
+        assert_eq!(decode_candles(&bytes), Some(candles.to_vec()));
+
+        assert_eq!(encode_candles(&[]), [0, 0, 0, 0]);
+        assert_eq!(decode_candles(&[0, 0, 0, 0]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn empty_buckets_are_all_zeros() {
+        let candles = [Candle::default(), candle(7, 7, 7, 7, 1), Candle::default()];
+        let bytes = encode_candles(&candles);
+        assert!(bytes[4..4 + CANDLE_BYTES].iter().all(|&byte| byte == 0));
+        assert!(bytes[4 + 2 * CANDLE_BYTES..].iter().all(|&byte| byte == 0));
+        assert_eq!(decode_candles(&bytes), Some(candles.to_vec()));
+    }
+
+    #[test]
+    fn the_largest_response_round_trips() {
+        let candles: Vec<_> = (0..10_000)
+            .map(|i| candle(i, i + 2, i - 2, i + 1, i as u32))
+            .collect();
+        let bytes = encode_candles(&candles);
+        assert_eq!(bytes.len(), 4 + 10_000 * CANDLE_BYTES);
+        assert_eq!(decode_candles(&bytes), Some(candles));
+    }
+
+    #[test]
+    fn a_length_that_disagrees_with_the_records_is_rejected() {
+        let bytes = encode_candles(&[candle(1, 2, 3, 4, 5), candle(6, 7, 8, 9, 10)]);
+
+        assert_eq!(decode_candles(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(decode_candles(&bytes[..4 + CANDLE_BYTES]), None);
+        assert_eq!(decode_candles(&[bytes.as_slice(), &[0]].concat()), None);
+        assert_eq!(decode_candles(&bytes[..3]), None);
+
+        let mut overstated = bytes.clone();
+        overstated[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(decode_candles(&overstated), None);
+    }
+}
@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+
+/// What an insert does when the session already has a price at its timestamp.
+/// The protocol leaves this undefined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicatePolicy {
+    // Store every price, so each one counts towards the mean
+    KeepAll,
+    // The newest price replaces the stored one
+    LastWriteWins,
+    // The stored price stays and the new one is dropped
+    FirstWriteWins,
+    // The insert is a protocol error and the session is closed
+    Reject,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    // The address the TCP listener binds to
+    pub addr: String,
+
+    pub duplicate_policy: DuplicatePolicy,
+
+    // Accept the count, sum, min, max and median opcodes on top of the ones
+    // in the spec
+    pub extended_opcodes: bool,
+
+    // Where named datasets are persisted. The attach opcode is only accepted
+    // when this is set.
+    pub data_dir: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    /// Parses `<ipv4_address> <port> [--flag value]...` from the command line.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        if args.len() < 3 {
+            return Err(format!(
+                "usage: {} <ipv4_address> <port> [options]",
+                args.first()
+                    .map(String::as_str)
+                    .unwrap_or("means_to_an_end")
+            ));
+        }
+
+        let mut config = Self {
+            addr: format!("{}:{}", args[1], args[2]),
+            duplicate_policy: DuplicatePolicy::KeepAll,
+            extended_opcodes: false,
+            data_dir: None,
+        };
+
+        let mut options = args[3..].iter();
+        while let Some(flag) = options.next() {
+            // Switches that don't take a value
+            if flag == "--extended-opcodes" {
+                config.extended_opcodes = true;
+                continue;
+            }
+
+            let value = options
+                .next()
+                .ok_or_else(|| format!("missing value for {}", flag))?;
+
+            match flag.as_str() {
+                "--duplicates" => {
+                    config.duplicate_policy = match value.as_str() {
+                        "keep-all" => DuplicatePolicy::KeepAll,
+                        "last-write-wins" => DuplicatePolicy::LastWriteWins,
+                        "first-write-wins" => DuplicatePolicy::FirstWriteWins,
+                        "reject" => DuplicatePolicy::Reject,
+                        _ => return Err(format!("invalid value for {}: {}", flag, value)),
+                    };
+                }
+                "--data-dir" => config.data_dir = Some(PathBuf::from(value)),
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+
+        Ok(config)
+    }
+}
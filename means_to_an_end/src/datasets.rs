@@ -0,0 +1,440 @@
+// Named datasets kept on local disk, so a client can reconnect and pick up
+// where it left off. Each dataset is an append-only file of 8-byte records,
+// a big-endian i32 timestamp then a big-endian i32 price, in the order they
+// were stored. The file is replayed into a PriceIndex on startup.
+
+use crate::config::DuplicatePolicy;
+use crate::index::PriceIndex;
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const NAME_BYTES: usize = 8;
+
+const RECORD_BYTES: usize = 8;
+
+const EXTENSION: &str = "prices";
+
+// Appends are fsynced once this many are waiting, or once the oldest has
+// waited this long, whichever comes first
+const SYNC_BATCH: usize = 256;
+const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What an insert did to a dataset under the duplicate policy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Applied {
+    Stored,
+    // A first-write-wins duplicate, silently dropped
+    Dropped,
+    // A duplicate the reject policy turns into a protocol error
+    Rejected,
+}
+
+#[derive(Debug)]
+pub struct Dataset {
+    pub prices: PriceIndex,
+    // None for the anonymous, per-connection datasets
+    log: Option<Log>,
+}
+
+#[derive(Debug)]
+struct Log {
+    name: String,
+    file: File,
+    // Bytes of the file known to hold whole, synced records
+    synced_len: u64,
+    // Records stored since the last fsync, not yet written to the file
+    pending: Vec<u8>,
+    synced_at: Instant,
+    // Set when a failed write couldn't be undone, so the file may end in a
+    // partial record. Nothing more is appended after that.
+    broken: bool,
+}
+
+/// Every named dataset, shared between connections.
+#[derive(Clone, Debug)]
+pub struct Datasets {
+    dir: PathBuf,
+    open: Arc<Mutex<HashMap<String, Arc<Mutex<Dataset>>>>>,
+}
+
+impl Dataset {
+    /// A dataset that lives only as long as the connection using it.
+    pub fn in_memory() -> Self {
+        Self {
+            prices: PriceIndex::new(),
+            log: None,
+        }
+    }
+
+    /// Stores a price according to the duplicate policy, writing it to disk
+    /// first when the dataset is persistent.
+    pub fn insert(
+        &mut self,
+        timestamp: i32,
+        price: i32,
+        duplicate_policy: DuplicatePolicy,
+    ) -> io::Result<Applied> {
+        // Duplicates are settled before logging, so the file only ever holds
+        // prices that were stored
+        let duplicate =
+            duplicate_policy != DuplicatePolicy::KeepAll && self.prices.contains(timestamp);
+        match (duplicate_policy, duplicate) {
+            (DuplicatePolicy::FirstWriteWins, true) => return Ok(Applied::Dropped),
+            (DuplicatePolicy::Reject, true) => return Ok(Applied::Rejected),
+            _ => {}
+        }
+
+        if let Some(log) = &mut self.log {
+            log.append(timestamp, price)?;
+        }
+
+        if duplicate {
+            self.prices.replace(timestamp, price);
+        } else {
+            self.prices.insert(timestamp, price);
+        }
+        Ok(Applied::Stored)
+    }
+}
+
+impl Log {
+    // An error means the record isn't stored, and won't be written later
+    fn append(&mut self, timestamp: i32, price: i32) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::other(format!(
+                "dataset {} stopped taking writes after a failed one",
+                self.name
+            )));
+        }
+
+        self.pending.extend(timestamp.to_be_bytes());
+        self.pending.extend(price.to_be_bytes());
+
+        let batch_full = self.pending.len() >= SYNC_BATCH * RECORD_BYTES;
+        if batch_full || self.synced_at.elapsed() >= SYNC_INTERVAL {
+            if let Err(err) = self.sync() {
+                // The records before this one are already stored, so they
+                // stay behind for the next sync to retry
+                self.pending.truncate(self.pending.len() - RECORD_BYTES);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // Writes out and fsyncs the pending records. If that fails, whatever part
+    // of them reached the file is cut off again, so that a retry writes each
+    // of them exactly once.
+    fn sync(&mut self) -> io::Result<()> {
+        let result = self
+            .file
+            .write_all(&self.pending)
+            .and_then(|_| self.file.sync_data());
+        if let Err(err) = result {
+            if let Err(truncate_err) = self.file.set_len(self.synced_len) {
+                println!(
+                    "ERROR - Failed to roll back dataset {}: {}",
+                    self.name, truncate_err
+                );
+                self.broken = true;
+            }
+            return Err(err);
+        }
+
+        self.synced_len += self.pending.len() as u64;
+        self.pending.clear();
+        self.synced_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl Datasets {
+    /// Loads every dataset under dir, creating dir if needed. A record cut
+    /// short by a crash is truncated away before the rest are replayed.
+    pub fn recover(dir: &Path, duplicate_policy: DuplicatePolicy) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut open = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let Some(name) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|name| is_valid_name(name))
+            else {
+                continue;
+            };
+
+            let dataset = recover_dataset(&path, name, duplicate_policy)?;
+            open.insert(name.to_string(), Arc::new(Mutex::new(dataset)));
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            open: Arc::new(Mutex::new(open)),
+        })
+    }
+
+    /// The dataset called name, created empty if it doesn't exist yet.
+    pub fn attach(&self, name: &str) -> io::Result<Arc<Mutex<Dataset>>> {
+        let mut open = lock(&self.open);
+        if let Some(dataset) = open.get(name) {
+            return Ok(Arc::clone(dataset));
+        }
+
+        let path = self.dir.join(format!("{}.{}", name, EXTENSION));
+        let dataset = Dataset {
+            prices: PriceIndex::new(),
+            log: Some(open_log(&path, name)?),
+        };
+        let dataset = Arc::new(Mutex::new(dataset));
+        open.insert(name.to_string(), Arc::clone(&dataset));
+        Ok(dataset)
+    }
+
+    /// Starts a thread that fsyncs appends left waiting by quiet datasets.
+    pub fn spawn_syncer(&self) {
+        let open = Arc::clone(&self.open);
+        thread::spawn(move || loop {
+            thread::sleep(SYNC_INTERVAL);
+
+            let datasets: Vec<_> = lock(&open).values().cloned().collect();
+            for dataset in datasets {
+                let mut dataset = lock(&dataset);
+                let Some(log) = dataset
+                    .log
+                    .as_mut()
+                    .filter(|log| !log.pending.is_empty() && !log.broken)
+                else {
+                    continue;
+                };
+                if let Err(err) = log.sync() {
+                    println!("ERROR - Failed to sync dataset {}: {}", log.name, err);
+                }
+            }
+        });
+    }
+}
+
+/// Names are up to NAME_BYTES of letters, digits, '-' and '_', so they're
+/// always safe to use as file names.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= NAME_BYTES
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+/// Locks a dataset, or the registry. Poisoning is ignored so that a panic in
+/// one connection doesn't lock every other client out of the dataset.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn recover_dataset(
+    path: &Path,
+    name: &str,
+    duplicate_policy: DuplicatePolicy,
+) -> io::Result<Dataset> {
+    let mut bytes = fs::read(path)?;
+
+    let torn = bytes.len() % RECORD_BYTES;
+    if torn != 0 {
+        println!(
+            "INFO - Truncating {} bytes of a torn record from dataset {}",
+            torn, name
+        );
+        bytes.truncate(bytes.len() - torn);
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(bytes.len() as u64)?;
+        file.sync_all()?;
+    }
+
+    let mut dataset = Dataset::in_memory();
+    for record in bytes.chunks_exact(RECORD_BYTES) {
+        let timestamp = i32::from_be_bytes([record[0], record[1], record[2], record[3]]);
+        let price = i32::from_be_bytes([record[4], record[5], record[6], record[7]]);
+        // The policy may have changed since these were logged, so duplicates
+        // are settled the way a live insert would settle them. Nothing is
+        // written back while the dataset has no log.
+        dataset.insert(timestamp, price, duplicate_policy)?;
+    }
+    dataset.log = Some(open_log(path, name)?);
+
+    println!(
+        "INFO - Recovered dataset {} with {} records",
+        name,
+        bytes.len() / RECORD_BYTES
+    );
+    Ok(dataset)
+}
+
+fn open_log(path: &Path, name: &str) -> io::Result<Log> {
+    let file = match OpenOptions::new().create_new(true).append(true).open(path) {
+        Ok(file) => {
+            // A new file's directory entry is only durable once the directory
+            // itself is synced
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            File::open(dir)?.sync_all()?;
+            file
+        }
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            OpenOptions::new().append(true).open(path)?
+        }
+        Err(err) => return Err(err),
+    };
+
+    Ok(Log {
+        name: name.to_string(),
+        synced_len: file.metadata()?.len(),
+        file,
+        pending: Vec::new(),
+        synced_at: Instant::now(),
+        broken: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::Summary;
+    use std::env;
+    use uuid::Uuid;
+
+    // A fresh directory under the system's temp dir, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = env::temp_dir().join(format!("means_to_an_end-{}", Uuid::new_v4()));
+            fs::create_dir(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn records(prices: &[(i32, i32)]) -> Vec<u8> {
+        prices
+            .iter()
+            .flat_map(|(timestamp, price)| [timestamp.to_be_bytes(), price.to_be_bytes()])
+            .flatten()
+            .collect()
+    }
+
+    fn summarize(datasets: &Datasets, name: &str, mintime: i32, maxtime: i32) -> Summary {
+        let dataset = datasets.attach(name).unwrap();
+        let summary = lock(&dataset).prices.summarize(mintime, maxtime);
+        summary
+    }
+
+    #[test]
+    fn a_torn_record_is_truncated_on_recovery() {
+        let dir = TempDir::new();
+        let path = dir.0.join("ticker.prices");
+        let mut bytes = records(&[(1, 10), (2, 20)]);
+        bytes.extend([0, 0, 3]);
+        fs::write(&path, bytes).unwrap();
+
+        let datasets = Datasets::recover(&dir.0, DuplicatePolicy::KeepAll).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 16);
+        let summary = summarize(&datasets, "ticker", i32::MIN, i32::MAX);
+        assert_eq!((summary.count, summary.sum), (2, 30));
+
+        // New records go after the last whole one
+        let dataset = datasets.attach("ticker").unwrap();
+        let mut dataset = lock(&dataset);
+        dataset.insert(3, 30, DuplicatePolicy::KeepAll).unwrap();
+        dataset.log.as_mut().unwrap().sync().unwrap();
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            records(&[(1, 10), (2, 20), (3, 30)])
+        );
+    }
+
+    #[test]
+    fn replay_settles_duplicates_under_the_current_policy() {
+        let dir = TempDir::new();
+        fs::write(
+            dir.0.join("ticker.prices"),
+            records(&[(1, 10), (1, 30), (2, 50)]),
+        )
+        .unwrap();
+
+        for (duplicate_policy, count, sum) in [
+            (DuplicatePolicy::KeepAll, 2, 40),
+            (DuplicatePolicy::LastWriteWins, 1, 30),
+            (DuplicatePolicy::FirstWriteWins, 1, 10),
+            (DuplicatePolicy::Reject, 1, 10),
+        ] {
+            let datasets = Datasets::recover(&dir.0, duplicate_policy).unwrap();
+            let summary = summarize(&datasets, "ticker", 1, 1);
+            assert_eq!(
+                (summary.count, summary.sum),
+                (count, sum),
+                "{:?}",
+                duplicate_policy
+            );
+            assert_eq!(summarize(&datasets, "ticker", 2, 2).sum, 50);
+        }
+    }
+
+    #[test]
+    fn a_failed_write_stores_nothing() {
+        let dir = TempDir::new();
+        let path = dir.0.join("ticker.prices");
+        fs::write(&path, records(&[(1, 10)])).unwrap();
+
+        // A read-only handle fails the write and the truncation after it, the
+        // worst case, where the log can't be trusted with more records
+        let mut dataset = Dataset::in_memory();
+        dataset.prices.insert(1, 10);
+        dataset.log = Some(Log {
+            name: "ticker".to_string(),
+            file: File::open(&path).unwrap(),
+            synced_len: 8,
+            pending: Vec::new(),
+            synced_at: Instant::now() - SYNC_INTERVAL,
+            broken: false,
+        });
+
+        assert!(dataset.insert(2, 20, DuplicatePolicy::KeepAll).is_err());
+        assert!(dataset.insert(3, 30, DuplicatePolicy::KeepAll).is_err());
+        let summary = dataset.prices.summarize(i32::MIN, i32::MAX);
+        assert_eq!((summary.count, summary.sum), (1, 10));
+        assert!(dataset.log.unwrap().pending.is_empty());
+        assert_eq!(fs::read(&path).unwrap(), records(&[(1, 10)]));
+    }
+
+    #[test]
+    fn a_new_dataset_is_recovered() {
+        let dir = TempDir::new();
+        let datasets = Datasets::recover(&dir.0, DuplicatePolicy::KeepAll).unwrap();
+        let dataset = datasets.attach("fresh").unwrap();
+        lock(&dataset)
+            .insert(1, 10, DuplicatePolicy::KeepAll)
+            .unwrap();
+        lock(&dataset).log.as_mut().unwrap().sync().unwrap();
+
+        let recovered = Datasets::recover(&dir.0, DuplicatePolicy::KeepAll).unwrap();
+        assert_eq!(summarize(&recovered, "fresh", 1, 1).sum, 10);
+    }
+}
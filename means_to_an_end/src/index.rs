@@ -0,0 +1,559 @@
+use std::hash::{BuildHasher, RandomState};
+
+// Prices ordered by timestamp in a treap: a binary search tree on timestamp
+// that stays balanced by also heap-ordering random priorities. Every node
+// caches a summary of its subtree, so any timestamp range can be summarised
+// by walking O(log n) nodes instead of scanning every insert. The tree is
+// only balanced if clients can't guess the priorities, so every index seeds
+// its generator at random, and no walk recurses in case one ever isn't.
+#[derive(Debug)]
+pub struct PriceIndex {
+    // Nodes live in an arena and refer to each other by position
+    nodes: Vec<Node>,
+    root: Option<usize>,
+    // State for the priority generator
+    seed: u64,
+}
+
+#[derive(Debug)]
+struct Node {
+    timestamp: i32,
+    price: i32,
+    priority: u64,
+    left: Option<usize>,
+    right: Option<usize>,
+    // Covers this node and both of its subtrees
+    summary: Summary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub count: u64,
+    pub sum: i64,
+    // Only meaningful when count is non-zero
+    pub min: i32,
+    pub max: i32,
+}
+
+impl Default for Summary {
+    // The empty summary, which leaves anything it's combined with unchanged
+    fn default() -> Self {
+        Summary {
+            count: 0,
+            sum: 0,
+            min: i32::MAX,
+            max: i32::MIN,
+        }
+    }
+}
+
+impl Summary {
+    fn single(price: i32) -> Self {
+        Summary {
+            count: 1,
+            sum: price as i64,
+            min: price,
+            max: price,
+        }
+    }
+
+    fn combine(self, other: Summary) -> Self {
+        Summary {
+            count: self.count + other.count,
+            sum: self.sum + other.sum,
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+}
+
+impl Default for PriceIndex {
+    fn default() -> Self {
+        PriceIndex {
+            nodes: Vec::new(),
+            root: None,
+            seed: RandomState::new().hash_one(0),
+        }
+    }
+}
+
+impl PriceIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, timestamp: i32, price: i32) {
+        let priority = self.next_priority();
+        self.insert_with_priority(timestamp, price, priority);
+    }
+
+    fn insert_with_priority(&mut self, timestamp: i32, price: i32, priority: u64) {
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            timestamp,
+            price,
+            priority,
+            left: None,
+            right: None,
+            summary: Summary::single(price),
+        });
+
+        // Walk down to where the new node belongs, which is above the first
+        // node it outranks
+        let mut path = Vec::new();
+        let mut current = self.root;
+        while let Some(index) = current {
+            if priority > self.nodes[index].priority {
+                break;
+            }
+            path.push(index);
+            current = if timestamp < self.nodes[index].timestamp {
+                self.nodes[index].left
+            } else {
+                self.nodes[index].right
+            };
+        }
+
+        // The subtree it displaces is split around it
+        let (left, right) = self.split(current, timestamp);
+        self.nodes[node].left = left;
+        self.nodes[node].right = right;
+        self.update(node);
+
+        match path.last() {
+            None => self.root = Some(node),
+            Some(&parent) if timestamp < self.nodes[parent].timestamp => {
+                self.nodes[parent].left = Some(node)
+            }
+            Some(&parent) => self.nodes[parent].right = Some(node),
+        }
+        self.update_path(&path);
+    }
+
+    pub fn contains(&self, timestamp: i32) -> bool {
+        let mut current = self.root;
+        while let Some(index) = current {
+            let node = &self.nodes[index];
+            if timestamp == node.timestamp {
+                return true;
+            }
+            current = if timestamp < node.timestamp {
+                node.left
+            } else {
+                node.right
+            };
+        }
+        false
+    }
+
+    /// Replaces the price stored at timestamp. Returns false, leaving the
+    /// index untouched, when there's nothing at that timestamp.
+    pub fn replace(&mut self, timestamp: i32, price: i32) -> bool {
+        let mut path = Vec::new();
+        let mut current = self.root;
+        while let Some(index) = current {
+            path.push(index);
+            let node = &mut self.nodes[index];
+            if timestamp == node.timestamp {
+                node.price = price;
+                self.update_path(&path);
+                return true;
+            }
+            current = if timestamp < node.timestamp {
+                node.left
+            } else {
+                node.right
+            };
+        }
+        false
+    }
+
+    /// The price with the earliest timestamp in [mintime, maxtime]. Among
+    /// equal timestamps that's the first one inserted.
+    pub fn first_in(&self, mintime: i32, maxtime: i32) -> Option<i32> {
+        let mut current = self.root;
+        let mut found = None;
+        while let Some(index) = current {
+            let node = &self.nodes[index];
+            if node.timestamp >= mintime {
+                found = Some(node);
+                current = node.left;
+            } else {
+                current = node.right;
+            }
+        }
+        found
+            .filter(|node| node.timestamp <= maxtime)
+            .map(|node| node.price)
+    }
+
+    /// The price with the latest timestamp in [mintime, maxtime]. Among
+    /// equal timestamps that's the last one inserted.
+    pub fn last_in(&self, mintime: i32, maxtime: i32) -> Option<i32> {
+        let mut current = self.root;
+        let mut found = None;
+        while let Some(index) = current {
+            let node = &self.nodes[index];
+            if node.timestamp <= maxtime {
+                found = Some(node);
+                current = node.right;
+            } else {
+                current = node.left;
+            }
+        }
+        found
+            .filter(|node| node.timestamp >= mintime)
+            .map(|node| node.price)
+    }
+
+    /// Every price with mintime <= timestamp <= maxtime, in timestamp order.
+    /// Takes O(log n) plus the number of prices returned.
+    pub fn prices_in(&self, mintime: i32, maxtime: i32) -> Vec<i32> {
+        let mut prices = Vec::new();
+
+        // An in-order walk that only descends into subtrees that can still
+        // hold in-range timestamps
+        let mut pending = Vec::new();
+        let mut current = self.root;
+        loop {
+            while let Some(index) = current {
+                let node = &self.nodes[index];
+                pending.push(index);
+                current = node.left.filter(|_| node.timestamp >= mintime);
+            }
+            let Some(index) = pending.pop() else {
+                break;
+            };
+
+            let node = &self.nodes[index];
+            if node.timestamp >= mintime && node.timestamp <= maxtime {
+                prices.push(node.price);
+            }
+            current = node.right.filter(|_| node.timestamp <= maxtime);
+        }
+
+        prices
+    }
+
+    /// Count, sum, min and max of the prices with mintime <= timestamp <= maxtime.
+    pub fn summarize(&self, mintime: i32, maxtime: i32) -> Summary {
+        let mut current = self.root;
+
+        // Walk down to the first node inside the range. Everything in range
+        // then sits in its left subtree (at or after mintime), the node itself
+        // and its right subtree (at or before maxtime).
+        while let Some(index) = current {
+            let node = &self.nodes[index];
+            if node.timestamp < mintime {
+                current = node.right;
+            } else if node.timestamp > maxtime {
+                current = node.left;
+            } else {
+                return self
+                    .summarize_from(node.left, mintime)
+                    .combine(Summary::single(node.price))
+                    .combine(self.summarize_until(node.right, maxtime));
+            }
+        }
+
+        Summary::default()
+    }
+
+    // Summary of the nodes under `node` with timestamp >= mintime
+    fn summarize_from(&self, mut node: Option<usize>, mintime: i32) -> Summary {
+        let mut summary = Summary::default();
+        while let Some(index) = node {
+            let current = &self.nodes[index];
+            if current.timestamp >= mintime {
+                summary = summary
+                    .combine(Summary::single(current.price))
+                    .combine(self.summary_of(current.right));
+                node = current.left;
+            } else {
+                node = current.right;
+            }
+        }
+        summary
+    }
+
+    // Summary of the nodes under `node` with timestamp <= maxtime
+    fn summarize_until(&self, mut node: Option<usize>, maxtime: i32) -> Summary {
+        let mut summary = Summary::default();
+        while let Some(index) = node {
+            let current = &self.nodes[index];
+            if current.timestamp <= maxtime {
+                summary = summary
+                    .combine(Summary::single(current.price))
+                    .combine(self.summary_of(current.left));
+                node = current.right;
+            } else {
+                node = current.left;
+            }
+        }
+        summary
+    }
+
+    fn summary_of(&self, node: Option<usize>) -> Summary {
+        node.map_or(Summary::default(), |index| self.nodes[index].summary)
+    }
+
+    // Splits a subtree into the nodes at or before timestamp and the rest.
+    // Keeping equal timestamps on the left puts a new node after the ones
+    // already stored, so prices at one timestamp stay in insertion order.
+    fn split(&mut self, root: Option<usize>, timestamp: i32) -> (Option<usize>, Option<usize>) {
+        let (mut left, mut right) = (None, None);
+        // The last node added to each side. The next one for the left side
+        // hangs off its tail's right, and the other way round.
+        let (mut left_tail, mut right_tail): (Option<usize>, Option<usize>) = (None, None);
+        let mut path = Vec::new();
+
+        let mut current = root;
+        while let Some(index) = current {
+            path.push(index);
+            if self.nodes[index].timestamp <= timestamp {
+                match left_tail {
+                    Some(tail) => self.nodes[tail].right = Some(index),
+                    None => left = Some(index),
+                }
+                left_tail = Some(index);
+                current = self.nodes[index].right;
+            } else {
+                match right_tail {
+                    Some(tail) => self.nodes[tail].left = Some(index),
+                    None => right = Some(index),
+                }
+                right_tail = Some(index);
+                current = self.nodes[index].left;
+            }
+        }
+
+        // The tails still point at nodes that went to the other side
+        if let Some(tail) = left_tail {
+            self.nodes[tail].right = None;
+        }
+        if let Some(tail) = right_tail {
+            self.nodes[tail].left = None;
+        }
+        self.update_path(&path);
+
+        (left, right)
+    }
+
+    // Refreshes the summaries along a path from the root, bottom first, so
+    // each node is recomputed after the children below it
+    fn update_path(&mut self, path: &[usize]) {
+        for &index in path.iter().rev() {
+            self.update(index);
+        }
+    }
+
+    // Recomputes a node's summary from its children
+    fn update(&mut self, index: usize) {
+        let node = &self.nodes[index];
+        let summary = self
+            .summary_of(node.left)
+            .combine(Summary::single(node.price))
+            .combine(self.summary_of(node.right));
+        self.nodes[index].summary = summary;
+    }
+
+    // SplitMix64: cheap, well mixed, and needs no extra dependency
+    fn next_priority(&mut self) -> u64 {
+        self.seed = self.seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // The Vec of (timestamp, price) pairs the index replaced, scanned in full
+    // for every query
+    fn scan(prices: &[(i32, i32)], mintime: i32, maxtime: i32) -> Summary {
+        prices
+            .iter()
+            .filter(|(timestamp, _)| (mintime..=maxtime).contains(timestamp))
+            .fold(Summary::default(), |summary, &(_, price)| {
+                summary.combine(Summary::single(price))
+            })
+    }
+
+    // xorshift64, so the tests are repeatable without a rand dependency
+    fn random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    // Timestamps are drawn from a narrow range so plenty of them repeat
+    fn random_prices(count: usize, state: &mut u64) -> Vec<(i32, i32)> {
+        (0..count)
+            .map(|_| {
+                let timestamp = (random(state) % 10_000) as i32 - 5_000;
+                (timestamp, random(state) as i32)
+            })
+            .collect()
+    }
+
+    fn random_range(state: &mut u64) -> (i32, i32) {
+        let mintime = (random(state) % 12_000) as i32 - 6_000;
+        (mintime, mintime + (random(state) % 3_000) as i32)
+    }
+
+    #[test]
+    fn queries_match_a_full_scan() {
+        let mut state = 0x5EED;
+        let prices = random_prices(5_000, &mut state);
+
+        let mut index = PriceIndex::new();
+        for &(timestamp, price) in &prices {
+            index.insert(timestamp, price);
+        }
+
+        for _ in 0..1_000 {
+            let (mintime, maxtime) = random_range(&mut state);
+            assert_eq!(
+                index.summarize(mintime, maxtime),
+                scan(&prices, mintime, maxtime)
+            );
+
+            let mut in_range: Vec<_> = prices
+                .iter()
+                .filter(|(timestamp, _)| (mintime..=maxtime).contains(timestamp))
+                .collect();
+            // A stable sort keeps equal timestamps in insertion order
+            in_range.sort_by_key(|(timestamp, _)| *timestamp);
+            let expected: Vec<_> = in_range.iter().map(|(_, price)| *price).collect();
+
+            assert_eq!(index.prices_in(mintime, maxtime), expected);
+            assert_eq!(index.first_in(mintime, maxtime), expected.first().copied());
+            assert_eq!(index.last_in(mintime, maxtime), expected.last().copied());
+        }
+    }
+
+    #[test]
+    fn an_empty_range_has_the_empty_summary() {
+        let mut index = PriceIndex::new();
+        assert_eq!(index.summarize(i32::MIN, i32::MAX), Summary::default());
+
+        index.insert(10, 100);
+        assert_eq!(index.summarize(11, 20), Summary::default());
+        assert_eq!(index.summarize(10, 9), Summary::default());
+        assert_eq!(index.summarize(10, 10), Summary::single(100));
+    }
+
+    fn depth(index: &PriceIndex) -> usize {
+        let mut deepest = 0;
+        let mut pending: Vec<_> = index.root.map(|root| (root, 1)).into_iter().collect();
+        while let Some((node, depth)) = pending.pop() {
+            deepest = deepest.max(depth);
+            let node = &index.nodes[node];
+            pending.extend(node.left.map(|left| (left, depth + 1)));
+            pending.extend(node.right.map(|right| (right, depth + 1)));
+        }
+        deepest
+    }
+
+    #[test]
+    fn guessed_priorities_dont_unbalance_the_tree() {
+        const INSERTS: usize = 100_000;
+
+        // When every index started from seed 0 its priorities were known in
+        // advance. Timestamps ranked in the same order as those priorities
+        // turned the tree into a chain.
+        let mut predicted = PriceIndex::new();
+        predicted.seed = 0;
+        let priorities: Vec<_> = (0..INSERTS).map(|_| predicted.next_priority()).collect();
+        let mut by_priority: Vec<_> = (0..INSERTS).collect();
+        by_priority.sort_by_key(|&insert| priorities[insert]);
+        let mut timestamps = vec![0; INSERTS];
+        for (rank, &insert) in by_priority.iter().enumerate() {
+            timestamps[insert] = rank as i32;
+        }
+
+        let mut index = PriceIndex::new();
+        for &timestamp in &timestamps {
+            index.insert(timestamp, 1);
+        }
+        // A balanced treap of this size is around 40 deep
+        assert!(depth(&index) < 100, "depth {}", depth(&index));
+        assert_eq!(index.summarize(i32::MIN, i32::MAX).count, INSERTS as u64);
+    }
+
+    #[test]
+    fn a_chain_doesnt_overflow_the_stack() {
+        const INSERTS: i32 = 1_000_000;
+
+        // Rising timestamps with rising priorities put each node on top of
+        // the last, which builds a chain without a quadratic insert
+        let mut index = PriceIndex::new();
+        for timestamp in 0..INSERTS {
+            index.insert_with_priority(timestamp, timestamp, timestamp as u64 + 1);
+        }
+        assert_eq!(depth(&index), INSERTS as usize);
+
+        // Each of these walks the whole chain
+        index.insert_with_priority(-1, -1, 0);
+        assert!(index.replace(-1, -2));
+        assert_eq!(index.prices_in(i32::MIN, -1), [-2]);
+        assert_eq!(
+            index.prices_in(i32::MIN, i32::MAX).len(),
+            INSERTS as usize + 1
+        );
+        assert_eq!(index.summarize(i32::MIN, 0).sum, -2);
+    }
+
+    // A benchmark rather than a test. Run it with
+    // `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn summarize_outpaces_a_full_scan() {
+        const INSERTS: usize = 1_000_000;
+        const QUERIES: usize = 1_000;
+
+        let mut state = 0x5EED;
+        let prices: Vec<_> = (0..INSERTS)
+            .map(|_| (random(&mut state) as i32, random(&mut state) as i32 >> 8))
+            .collect();
+        let ranges: Vec<_> = (0..QUERIES)
+            .map(|_| {
+                let (a, b) = (random(&mut state) as i32, random(&mut state) as i32);
+                (a.min(b), a.max(b))
+            })
+            .collect();
+
+        let started = Instant::now();
+        let mut index = PriceIndex::new();
+        for &(timestamp, price) in &prices {
+            index.insert(timestamp, price);
+        }
+        let built = started.elapsed();
+
+        let started = Instant::now();
+        let indexed: Vec<_> = ranges
+            .iter()
+            .map(|&(mintime, maxtime)| index.summarize(mintime, maxtime))
+            .collect();
+        let index_time = started.elapsed();
+
+        let started = Instant::now();
+        let scanned: Vec<_> = ranges
+            .iter()
+            .map(|&(mintime, maxtime)| scan(&prices, mintime, maxtime))
+            .collect();
+        let scan_time = started.elapsed();
+
+        assert_eq!(indexed, scanned);
+        println!(
+            "{} inserts took {:?}. {} queries took {:?} on the index and {:?} scanning the Vec.",
+            INSERTS, built, QUERIES, index_time, scan_time
+        );
+        assert!(index_time < scan_time);
+    }
+}
@@ -1,16 +1,52 @@
+mod candles;
+mod config;
+mod datasets;
+mod index;
+
+use candles::{encode_candles, Candle};
+use config::{DuplicatePolicy, ServerConfig};
+use datasets::{Applied, Dataset, Datasets, NAME_BYTES};
 use std::{
     env,
     io::{BufReader, Read, Write},
     net::{TcpListener, TcpStream},
+    process,
+    sync::{Arc, Mutex, MutexGuard},
     thread,
 };
 use uuid::Uuid;
 
+// Most buckets a single candle query may ask for
+const MAX_CANDLES: i64 = 10_000;
+
+// Every request is a one-byte opcode followed by two big-endian i32s. The
+// extended opcodes are only accepted with --extended-opcodes, and each takes
+// the same [mintime, maxtime] range as a query. An empty range answers zero.
+// 'N' is only accepted with --data-dir.
 #[derive(Debug)]
 enum Request {
     Invalid,
+    // 'I': no response
     Insert(InsertRequest),
+    // 'Q': mean price, 4 bytes i32
     Query(QueryRequest),
+    // 'C': number of prices, 4 bytes u32
+    Count(QueryRequest),
+    // 'S': sum of the prices, 8 bytes i64
+    Sum(QueryRequest),
+    // 'm': lowest price, 4 bytes i32
+    Min(QueryRequest),
+    // 'X': highest price, 4 bytes i32
+    Max(QueryRequest),
+    // 'M': median price, 4 bytes i32. An even count takes the mean of the two
+    // middle prices, rounded toward zero like 'Q'.
+    Median(QueryRequest),
+    // 'K': OHLC candles, with a third i32 for the bucket width, so 13 bytes
+    // in all. Answered with the variable-length format in candles.rs.
+    Candles(CandleRequest),
+    // 'N': attach to the named dataset, no response. The name is the other 8
+    // bytes, padded with trailing zeros. Only valid as the first request.
+    Attach(String),
 }
 
 #[derive(Debug)]
@@ -26,44 +62,79 @@ struct QueryRequest {
 }
 
 #[derive(Debug)]
-struct Transaction {
-    timestamp: i32,
-    price: i32,
+struct CandleRequest {
+    mintime: i32,
+    maxtime: i32,
+    // Each bucket covers this many timestamps, starting from mintime
+    width: i32,
 }
 
 #[derive(Debug)]
 struct SessionState {
     session_id: String,
-    client_transactions: Vec<Transaction>,
+    // The client's prices. Private to the connection unless it attaches to a
+    // named dataset, which other connections may share.
+    dataset: Arc<Mutex<Dataset>>,
+}
+
+impl SessionState {
+    fn dataset(&self) -> MutexGuard<'_, Dataset> {
+        datasets::lock(&self.dataset)
+    }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let ipv4_address = args[1].clone();
-    let port = args[2].clone();
-    let addr = format!("{}:{}", ipv4_address, port);
+    let config = match ServerConfig::from_args(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    };
+
+    // Recover every persisted dataset before accepting any connections
+    let datasets = config.data_dir.as_ref().map(|data_dir| {
+        match Datasets::recover(data_dir, config.duplicate_policy) {
+            Ok(datasets) => datasets,
+            Err(err) => {
+                eprintln!(
+                    "failed to recover datasets from {}: {}",
+                    data_dir.display(),
+                    err
+                );
+                process::exit(1);
+            }
+        }
+    });
+    if let Some(datasets) = &datasets {
+        datasets.spawn_syncer();
+    }
 
-    let listener = TcpListener::bind(&addr).unwrap();
-    serve(listener);
+    let listener = TcpListener::bind(&config.addr).unwrap();
+    serve(listener, config, datasets);
 }
 
-fn serve(listener: TcpListener) {
+fn serve(listener: TcpListener, config: ServerConfig, datasets: Option<Datasets>) {
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                thread::spawn(|| handle_connection(stream));
+                let config = config.clone();
+                let datasets = datasets.clone();
+                thread::spawn(move || handle_connection(stream, config, datasets));
             }
             Err(err) => panic!("Failed while listening for incoming connections: {}", err),
         }
     }
 }
 
-fn handle_connection(mut stream: TcpStream) {
+fn handle_connection(mut stream: TcpStream, config: ServerConfig, datasets: Option<Datasets>) {
     // Track the client transactions and randomly generated session ID
     let mut session_state = SessionState {
         session_id: Uuid::new_v4().to_string(),
-        client_transactions: Vec::new(),
+        dataset: Arc::new(Mutex::new(Dataset::in_memory())),
     };
+    let mut first_request = true;
 
     println!("{} - INFO - New session created", session_state.session_id);
 
@@ -77,13 +148,69 @@ fn handle_connection(mut stream: TcpStream) {
             break;
         }
 
-        match parse_request(read_buffer) {
+        let request = if read_buffer[0] == b'K' && config.extended_opcodes {
+            // Candle queries carry the bucket width after the usual 9 bytes
+            let mut width_bytes = [0u8; 4];
+            if stream.read_exact(&mut width_bytes).is_err() {
+                println!(
+                    "{} - INFO - Session terminated by client",
+                    session_state.session_id
+                );
+                break;
+            }
+            parse_candle_request(read_buffer, width_bytes)
+        } else {
+            parse_request(read_buffer, &config)
+        };
+
+        match request {
             Request::Insert(insert_request) => {
-                handle_insert(insert_request, &mut session_state);
+                if !handle_insert(insert_request, &mut session_state, config.duplicate_policy) {
+                    respond_failure(&stream, &session_state);
+                    // Break so we terminate the connection
+                    break;
+                }
             }
             Request::Query(query_request) => {
                 let result = handle_query(query_request, &session_state);
-                respond_success(&stream, &session_state, result);
+                respond_success(&stream, &session_state, &result);
+            }
+            Request::Count(query_request) => {
+                let result = handle_count(query_request, &session_state);
+                respond_success(&stream, &session_state, &result);
+            }
+            Request::Sum(query_request) => {
+                let result = handle_sum(query_request, &session_state);
+                respond_success(&stream, &session_state, &result);
+            }
+            Request::Min(query_request) => {
+                let result = handle_min(query_request, &session_state);
+                respond_success(&stream, &session_state, &result);
+            }
+            Request::Max(query_request) => {
+                let result = handle_max(query_request, &session_state);
+                respond_success(&stream, &session_state, &result);
+            }
+            Request::Median(query_request) => {
+                let result = handle_median(query_request, &session_state);
+                respond_success(&stream, &session_state, &result);
+            }
+            Request::Candles(candle_request) => {
+                let result = handle_candles(candle_request, &session_state);
+                respond_success(&stream, &session_state, &result);
+            }
+            Request::Attach(name) => {
+                let attached = match &datasets {
+                    Some(datasets) if first_request => {
+                        handle_attach(name, datasets, &mut session_state)
+                    }
+                    _ => false,
+                };
+                if !attached {
+                    respond_failure(&stream, &session_state);
+                    // Break so we terminate the connection
+                    break;
+                }
             }
             Request::Invalid => {
                 respond_failure(&stream, &session_state);
@@ -91,6 +218,8 @@ fn handle_connection(mut stream: TcpStream) {
                 break;
             }
         }
+
+        first_request = false;
     }
 
     println!(
@@ -101,7 +230,7 @@ fn handle_connection(mut stream: TcpStream) {
 
 // Request Parsing
 
-fn parse_request(raw_bytes: [u8; 9]) -> Request {
+fn parse_request(raw_bytes: [u8; 9], config: &ServerConfig) -> Request {
     // Use a BufReader to read specific sets of bytes from the raw_bytes
     let mut request_buf = BufReader::new(&raw_bytes[..]);
 
@@ -115,12 +244,23 @@ fn parse_request(raw_bytes: [u8; 9]) -> Request {
     let op_code = op_code_bytes[0] as char;
 
     // Handle the op code appropriately
-    if op_code == 'I' {
-        return parse_insert_request(request_buf);
-    } else if op_code == 'Q' {
-        return parse_query_request(request_buf);
-    } else {
-        Request::Invalid
+    match op_code {
+        'I' => parse_insert_request(request_buf),
+        'Q' => parse_query_request(request_buf).map_or(Request::Invalid, Request::Query),
+        'C' | 'S' | 'm' | 'X' | 'M' if config.extended_opcodes => {
+            let Some(query_request) = parse_query_request(request_buf) else {
+                return Request::Invalid;
+            };
+            match op_code {
+                'C' => Request::Count(query_request),
+                'S' => Request::Sum(query_request),
+                'm' => Request::Min(query_request),
+                'X' => Request::Max(query_request),
+                _ => Request::Median(query_request),
+            }
+        }
+        'N' if config.data_dir.is_some() => parse_attach_request(request_buf),
+        _ => Request::Invalid,
     }
 }
 
@@ -141,36 +281,119 @@ fn parse_insert_request(mut request_buf: BufReader<&[u8]>) -> Request {
     Request::Insert(InsertRequest { timestamp, price })
 }
 
-fn parse_query_request(mut request_buf: BufReader<&[u8]>) -> Request {
+// Shared by every opcode that takes a [mintime, maxtime] range
+fn parse_query_request(mut request_buf: BufReader<&[u8]>) -> Option<QueryRequest> {
     let mut mintime_bytes = [0u8; 4];
-    if request_buf.read_exact(&mut mintime_bytes).is_err() {
-        return Request::Invalid;
-    }
+    request_buf.read_exact(&mut mintime_bytes).ok()?;
 
     let mut maxtime_bytes = [0u8; 4];
-    if request_buf.read_exact(&mut maxtime_bytes).is_err() {
-        return Request::Invalid;
-    }
+    request_buf.read_exact(&mut maxtime_bytes).ok()?;
 
     let mintime = i32::from_be_bytes(mintime_bytes);
     let maxtime = i32::from_be_bytes(maxtime_bytes);
 
-    Request::Query(QueryRequest { mintime, maxtime })
+    Some(QueryRequest { mintime, maxtime })
+}
+
+fn parse_attach_request(mut request_buf: BufReader<&[u8]>) -> Request {
+    let mut name_bytes = [0u8; NAME_BYTES];
+    if request_buf.read_exact(&mut name_bytes).is_err() {
+        return Request::Invalid;
+    }
+
+    // Shorter names are padded out with zeros
+    let length = name_bytes
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |last| last + 1);
+    match std::str::from_utf8(&name_bytes[..length]) {
+        Ok(name) if datasets::is_valid_name(name) => Request::Attach(name.to_string()),
+        _ => Request::Invalid,
+    }
+}
+
+fn parse_candle_request(raw_bytes: [u8; 9], width_bytes: [u8; 4]) -> Request {
+    let Some(QueryRequest { mintime, maxtime }) =
+        parse_query_request(BufReader::new(&raw_bytes[1..]))
+    else {
+        return Request::Invalid;
+    };
+    let width = i32::from_be_bytes(width_bytes);
+
+    // Buckets need a positive width, and there's a cap on how many one query
+    // can produce
+    if width <= 0 {
+        return Request::Invalid;
+    }
+    let span = maxtime as i64 - mintime as i64;
+    if span >= 0 && span / width as i64 + 1 > MAX_CANDLES {
+        return Request::Invalid;
+    }
+
+    Request::Candles(CandleRequest {
+        mintime,
+        maxtime,
+        width,
+    })
 }
 
 // Request Handlers
 
-fn handle_insert(insert_request: InsertRequest, session_state: &mut SessionState) {
+// Returns false when the insert is rejected as a duplicate or can't be saved
+fn handle_insert(
+    insert_request: InsertRequest,
+    session_state: &mut SessionState,
+    duplicate_policy: DuplicatePolicy,
+) -> bool {
     println!(
         "{} - INFO - Handling insert request: {:?}",
         session_state.session_id, insert_request
     );
 
-    // Just append the transaction to the ClientTransactions
-    session_state.client_transactions.push(Transaction {
-        timestamp: insert_request.timestamp,
-        price: insert_request.price,
-    });
+    let InsertRequest { timestamp, price } = insert_request;
+    let applied = session_state
+        .dataset()
+        .insert(timestamp, price, duplicate_policy);
+
+    match applied {
+        Ok(Applied::Stored | Applied::Dropped) => true,
+        Ok(Applied::Rejected) => {
+            println!(
+                "{} - INFO - Rejecting duplicate timestamp {}",
+                session_state.session_id, timestamp
+            );
+            false
+        }
+        Err(err) => {
+            println!(
+                "{} - ERROR - Failed to persist insert: {}",
+                session_state.session_id, err
+            );
+            false
+        }
+    }
+}
+
+// Returns false when the dataset can't be opened
+fn handle_attach(name: String, datasets: &Datasets, session_state: &mut SessionState) -> bool {
+    println!(
+        "{} - INFO - Handling attach request: {:?}",
+        session_state.session_id, name
+    );
+
+    match datasets.attach(&name) {
+        Ok(dataset) => {
+            session_state.dataset = dataset;
+            true
+        }
+        Err(err) => {
+            println!(
+                "{} - ERROR - Failed to open dataset {}: {}",
+                session_state.session_id, name, err
+            );
+            false
+        }
+    }
 }
 
 fn handle_query(query_request: QueryRequest, session_state: &SessionState) -> [u8; 4] {
@@ -179,17 +402,12 @@ fn handle_query(query_request: QueryRequest, session_state: &SessionState) -> [u
         session_state.session_id, query_request
     );
 
-    let mut total: i64 = 0;
-    let mut txn_count: i64 = 0;
-    for i in 0..session_state.client_transactions.len() {
-        let txn = &session_state.client_transactions[i];
-        if txn.timestamp >= query_request.mintime && txn.timestamp <= query_request.maxtime {
-            total += txn.price as i64;
-            txn_count += 1;
-        }
-    }
+    let summary = session_state
+        .dataset()
+        .prices
+        .summarize(query_request.mintime, query_request.maxtime);
 
-    if txn_count == 0 {
+    if summary.count == 0 {
         println!(
             "{} - INFO - Found zero txns, returning zero...",
             session_state.session_id
@@ -197,18 +415,146 @@ fn handle_query(query_request: QueryRequest, session_state: &SessionState) -> [u
         return 0_i32.to_be_bytes();
     }
 
-    let bytes = (total / txn_count).to_be_bytes();
+    let bytes = (summary.sum / summary.count as i64).to_be_bytes();
     [bytes[4], bytes[5], bytes[6], bytes[7]]
 }
 
+fn handle_count(query_request: QueryRequest, session_state: &SessionState) -> [u8; 4] {
+    println!(
+        "{} - INFO - Handling count request: {:?}",
+        session_state.session_id, query_request
+    );
+
+    let summary = session_state
+        .dataset()
+        .prices
+        .summarize(query_request.mintime, query_request.maxtime);
+    u32::try_from(summary.count)
+        .unwrap_or(u32::MAX)
+        .to_be_bytes()
+}
+
+fn handle_sum(query_request: QueryRequest, session_state: &SessionState) -> [u8; 8] {
+    println!(
+        "{} - INFO - Handling sum request: {:?}",
+        session_state.session_id, query_request
+    );
+
+    let summary = session_state
+        .dataset()
+        .prices
+        .summarize(query_request.mintime, query_request.maxtime);
+    summary.sum.to_be_bytes()
+}
+
+fn handle_min(query_request: QueryRequest, session_state: &SessionState) -> [u8; 4] {
+    println!(
+        "{} - INFO - Handling min request: {:?}",
+        session_state.session_id, query_request
+    );
+
+    let summary = session_state
+        .dataset()
+        .prices
+        .summarize(query_request.mintime, query_request.maxtime);
+    if summary.count == 0 {
+        return 0_i32.to_be_bytes();
+    }
+    summary.min.to_be_bytes()
+}
+
+fn handle_max(query_request: QueryRequest, session_state: &SessionState) -> [u8; 4] {
+    println!(
+        "{} - INFO - Handling max request: {:?}",
+        session_state.session_id, query_request
+    );
+
+    let summary = session_state
+        .dataset()
+        .prices
+        .summarize(query_request.mintime, query_request.maxtime);
+    if summary.count == 0 {
+        return 0_i32.to_be_bytes();
+    }
+    summary.max.to_be_bytes()
+}
+
+fn handle_median(query_request: QueryRequest, session_state: &SessionState) -> [u8; 4] {
+    println!(
+        "{} - INFO - Handling median request: {:?}",
+        session_state.session_id, query_request
+    );
+
+    // Unlike the other aggregates this needs the prices themselves
+    let mut prices = session_state
+        .dataset()
+        .prices
+        .prices_in(query_request.mintime, query_request.maxtime);
+    if prices.is_empty() {
+        return 0_i32.to_be_bytes();
+    }
+
+    let middle = prices.len() / 2;
+    let even = prices.len().is_multiple_of(2);
+    let (below, &mut upper, _) = prices.select_nth_unstable(middle);
+    if !even {
+        return upper.to_be_bytes();
+    }
+
+    // The lower middle price is the largest of the ones below the upper
+    let lower = *below
+        .iter()
+        .max()
+        .expect("an even count leaves prices below the middle");
+    let median = (lower as i64 + upper as i64) / 2;
+    (median as i32).to_be_bytes()
+}
+
+fn handle_candles(candle_request: CandleRequest, session_state: &SessionState) -> Vec<u8> {
+    println!(
+        "{} - INFO - Handling candle request: {:?}",
+        session_state.session_id, candle_request
+    );
+
+    let dataset = session_state.dataset();
+    let prices = &dataset.prices;
+    let mut candles = Vec::new();
+
+    // Bucket bounds are worked out in i64 so the last one can't overflow
+    let maxtime = candle_request.maxtime as i64;
+    let mut start = candle_request.mintime as i64;
+    while start <= maxtime {
+        let end = (start + candle_request.width as i64 - 1).min(maxtime);
+        let (low, high) = (start as i32, end as i32);
+
+        let summary = prices.summarize(low, high);
+        candles.push(
+            match (prices.first_in(low, high), prices.last_in(low, high)) {
+                (Some(open), Some(close)) => Candle {
+                    open,
+                    high: summary.max,
+                    low: summary.min,
+                    close,
+                    count: u32::try_from(summary.count).unwrap_or(u32::MAX),
+                },
+                _ => Candle::default(),
+            },
+        );
+
+        start = end + 1;
+    }
+
+    encode_candles(&candles)
+}
+
 // TcpStream Utils
 
-fn respond_success(mut stream: &TcpStream, session_state: &SessionState, response: [u8; 4]) {
+fn respond_success(mut stream: &TcpStream, session_state: &SessionState, response: &[u8]) {
     println!(
         "{} - INFO - Responding to session client with {:?}",
         session_state.session_id, response
     );
-    stream.write_all(&response).unwrap();
+    stream.write_all(response).unwrap();
 }
 
 fn respond_failure(mut stream: &TcpStream, session_state: &SessionState) {
@@ -218,3 +564,296 @@ fn respond_failure(mut stream: &TcpStream, session_state: &SessionState) {
     );
     stream.write_all("\n".as_bytes()).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candles::decode_candles;
+    use std::{
+        fs,
+        net::SocketAddr,
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    // Starts a server without persistence on an ephemeral port
+    fn start_server(duplicate_policy: DuplicatePolicy) -> SocketAddr {
+        start_server_with(duplicate_policy, true)
+    }
+
+    fn start_server_with(duplicate_policy: DuplicatePolicy, extended_opcodes: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            addr: addr.to_string(),
+            duplicate_policy,
+            extended_opcodes,
+            data_dir: None,
+        };
+        thread::spawn(move || serve(listener, config, None));
+        addr
+    }
+
+    fn send(stream: &mut TcpStream, op_code: u8, first: i32, second: i32) {
+        let mut request = vec![op_code];
+        request.extend(first.to_be_bytes());
+        request.extend(second.to_be_bytes());
+        stream.write_all(&request).unwrap();
+    }
+
+    fn ask(stream: &mut TcpStream, op_code: u8, mintime: i32, maxtime: i32) -> i32 {
+        send(stream, op_code, mintime, maxtime);
+        let mut response = [0u8; 4];
+        stream.read_exact(&mut response).unwrap();
+        i32::from_be_bytes(response)
+    }
+
+    fn ask_sum(stream: &mut TcpStream, mintime: i32, maxtime: i32) -> i64 {
+        send(stream, b'S', mintime, maxtime);
+        let mut response = [0u8; 8];
+        stream.read_exact(&mut response).unwrap();
+        i64::from_be_bytes(response)
+    }
+
+    // Stores two prices at timestamp 100 and one at 200, then returns the
+    // count and mean at 100 and the mean across all three timestamps
+    fn insert_duplicates(duplicate_policy: DuplicatePolicy) -> (i32, i32, i32) {
+        let mut stream = TcpStream::connect(start_server(duplicate_policy)).unwrap();
+        send(&mut stream, b'I', 100, 10);
+        send(&mut stream, b'I', 100, 30);
+        send(&mut stream, b'I', 200, 50);
+        (
+            ask(&mut stream, b'C', 100, 100),
+            ask(&mut stream, b'Q', 100, 100),
+            ask(&mut stream, b'Q', 0, 300),
+        )
+    }
+
+    #[test]
+    fn keep_all_counts_every_duplicate() {
+        assert_eq!(insert_duplicates(DuplicatePolicy::KeepAll), (2, 20, 30));
+    }
+
+    #[test]
+    fn last_write_wins_replaces_the_stored_price() {
+        assert_eq!(
+            insert_duplicates(DuplicatePolicy::LastWriteWins),
+            (1, 30, 40)
+        );
+    }
+
+    #[test]
+    fn first_write_wins_drops_the_new_price() {
+        assert_eq!(
+            insert_duplicates(DuplicatePolicy::FirstWriteWins),
+            (1, 10, 30)
+        );
+    }
+
+    #[test]
+    fn reject_closes_the_session_on_a_duplicate() {
+        let mut stream = TcpStream::connect(start_server(DuplicatePolicy::Reject)).unwrap();
+        send(&mut stream, b'I', 100, 10);
+        send(&mut stream, b'I', 200, 50);
+        assert_eq!(ask(&mut stream, b'Q', 0, 300), 30);
+
+        send(&mut stream, b'I', 100, 30);
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"\n");
+    }
+
+    fn candle_request(mintime: i32, maxtime: i32, width: i32) -> Request {
+        let mut raw_bytes = [b'K'; 9];
+        raw_bytes[1..5].copy_from_slice(&mintime.to_be_bytes());
+        raw_bytes[5..].copy_from_slice(&maxtime.to_be_bytes());
+        parse_candle_request(raw_bytes, width.to_be_bytes())
+    }
+
+    #[test]
+    fn candle_requests_are_capped() {
+        assert!(matches!(candle_request(0, 9_999, 1), Request::Candles(_)));
+        assert!(matches!(candle_request(0, 10_000, 1), Request::Invalid));
+        assert!(matches!(
+            candle_request(1, 100_000, 10),
+            Request::Candles(_)
+        ));
+        assert!(matches!(candle_request(0, 100_000, 10), Request::Invalid));
+        assert!(matches!(
+            candle_request(i32::MIN, i32::MAX, i32::MAX),
+            Request::Candles(_)
+        ));
+        // An empty range is fine and answers no buckets
+        assert!(matches!(candle_request(10, 0, 1), Request::Candles(_)));
+
+        assert!(matches!(candle_request(0, 10, 0), Request::Invalid));
+        assert!(matches!(candle_request(0, 10, -1), Request::Invalid));
+    }
+
+    #[test]
+    fn candles_over_tcp() {
+        let mut stream = TcpStream::connect(start_server(DuplicatePolicy::KeepAll)).unwrap();
+        send(&mut stream, b'I', 0, 10);
+        send(&mut stream, b'I', 3, 40);
+        send(&mut stream, b'I', 1, 20);
+        send(&mut stream, b'I', 21, 5);
+
+        let mut request = vec![b'K'];
+        request.extend(0i32.to_be_bytes());
+        request.extend(29i32.to_be_bytes());
+        request.extend(10i32.to_be_bytes());
+        stream.write_all(&request).unwrap();
+
+        let mut response = [0u8; 4 + 3 * candles::CANDLE_BYTES];
+        stream.read_exact(&mut response).unwrap();
+        let expected = [
+            Candle {
+                open: 10,
+                high: 40,
+                low: 10,
+                close: 40,
+                count: 3,
+            },
+            Candle::default(),
+            Candle {
+                open: 5,
+                high: 5,
+                low: 5,
+                close: 5,
+                count: 1,
+            },
+        ];
+        assert_eq!(decode_candles(&response), Some(expected.to_vec()));
+    }
+
+    #[test]
+    fn extended_aggregates_over_tcp() {
+        let mut stream = TcpStream::connect(start_server(DuplicatePolicy::KeepAll)).unwrap();
+        send(&mut stream, b'I', 1, 10);
+        send(&mut stream, b'I', 2, -3);
+        send(&mut stream, b'I', 3, 7);
+
+        // An odd count has a middle price of its own
+        assert_eq!(ask(&mut stream, b'C', 0, 10), 3);
+        assert_eq!(ask_sum(&mut stream, 0, 10), 14);
+        assert_eq!(ask(&mut stream, b'm', 0, 10), -3);
+        assert_eq!(ask(&mut stream, b'X', 0, 10), 10);
+        assert_eq!(ask(&mut stream, b'M', 0, 10), 7);
+
+        // An even count takes the mean of the two middle prices, 7 and 10
+        send(&mut stream, b'I', 4, 20);
+        assert_eq!(ask(&mut stream, b'C', 0, 10), 4);
+        assert_eq!(ask(&mut stream, b'M', 0, 10), 8);
+
+        // Halves round toward zero, so -3.5 comes out as -3 and not -4
+        send(&mut stream, b'I', 20, -3);
+        send(&mut stream, b'I', 21, -4);
+        assert_eq!(ask(&mut stream, b'M', 20, 21), -3);
+        assert_eq!(ask(&mut stream, b'Q', 20, 21), -3);
+    }
+
+    #[test]
+    fn extended_aggregates_of_an_empty_range_are_zero() {
+        let mut stream = TcpStream::connect(start_server(DuplicatePolicy::KeepAll)).unwrap();
+        send(&mut stream, b'I', 1, 10);
+
+        for (mintime, maxtime) in [(100, 200), (10, 0)] {
+            for op_code in [b'C', b'm', b'X', b'M'] {
+                assert_eq!(ask(&mut stream, op_code, mintime, maxtime), 0);
+            }
+            assert_eq!(ask_sum(&mut stream, mintime, maxtime), 0);
+        }
+    }
+
+    #[test]
+    fn sums_past_i32_are_exact() {
+        let mut stream = TcpStream::connect(start_server(DuplicatePolicy::KeepAll)).unwrap();
+        for timestamp in 0..3 {
+            send(&mut stream, b'I', timestamp, i32::MAX);
+        }
+        send(&mut stream, b'I', 3, i32::MIN);
+        send(&mut stream, b'I', 4, i32::MIN);
+
+        assert_eq!(ask_sum(&mut stream, 0, 2), 3 * i32::MAX as i64);
+        assert_eq!(ask_sum(&mut stream, 3, 4), 2 * i32::MIN as i64);
+        assert_eq!(ask(&mut stream, b'Q', 0, 2), i32::MAX);
+        assert_eq!(ask(&mut stream, b'M', 0, 4), i32::MAX);
+    }
+
+    #[test]
+    fn extended_opcodes_need_the_flag() {
+        let addr = start_server_with(DuplicatePolicy::KeepAll, false);
+        for op_code in [b'C', b'S', b'm', b'X', b'M', b'K'] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            send(&mut stream, b'I', 1, 10);
+            send(&mut stream, op_code, 0, 10);
+
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, b"\n", "{}", op_code as char);
+        }
+    }
+
+    // Starts a server that recovers and persists datasets under data_dir
+    fn start_persistent_server(data_dir: &Path) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            addr: addr.to_string(),
+            duplicate_policy: DuplicatePolicy::KeepAll,
+            extended_opcodes: true,
+            data_dir: Some(data_dir.to_path_buf()),
+        };
+        let datasets = Datasets::recover(data_dir, config.duplicate_policy).unwrap();
+        datasets.spawn_syncer();
+        thread::spawn(move || serve(listener, config, Some(datasets)));
+        addr
+    }
+
+    fn attach(addr: SocketAddr, name: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = [0u8; 9];
+        request[0] = b'N';
+        request[1..=name.len()].copy_from_slice(name.as_bytes());
+        stream.write_all(&request).unwrap();
+        stream
+    }
+
+    #[test]
+    fn datasets_survive_a_restart() {
+        let data_dir = std::env::temp_dir().join(format!("means_to_an_end-{}", Uuid::new_v4()));
+        fs::create_dir(&data_dir).unwrap();
+        let path = data_dir.join("ticker.prices");
+
+        // Two whole records and the start of a third, as a crash mid-append
+        // would leave them
+        let mut bytes = Vec::new();
+        for (timestamp, price) in [(1, 10), (2, 30)] {
+            bytes.extend(i32::to_be_bytes(timestamp));
+            bytes.extend(i32::to_be_bytes(price));
+        }
+        bytes.extend([0, 0, 0, 3, 0]);
+        fs::write(&path, bytes).unwrap();
+
+        let mut stream = attach(start_persistent_server(&data_dir), "ticker");
+        assert_eq!(fs::metadata(&path).unwrap().len(), 16);
+        assert_eq!(ask(&mut stream, b'C', 0, 10), 2);
+        assert_eq!(ask(&mut stream, b'Q', 0, 10), 20);
+        send(&mut stream, b'I', 3, 50);
+        assert_eq!(ask(&mut stream, b'Q', 0, 10), 30);
+
+        // The syncer writes the insert out shortly after
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while fs::metadata(&path).unwrap().len() < 24 {
+            assert!(Instant::now() < deadline, "the insert never reached disk");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut stream = attach(start_persistent_server(&data_dir), "ticker");
+        assert_eq!(ask(&mut stream, b'C', 0, 10), 3);
+        assert_eq!(ask(&mut stream, b'M', 0, 10), 30);
+        assert_eq!(ask_sum(&mut stream, 0, 10), 90);
+
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+}
@@ -0,0 +1,158 @@
+//! Pipelining load generator for the line protocol. Each scenario opens one
+//! connection and keeps up to `window` requests outstanding. It reports
+//! throughput, and how many data segments carried the responses, read from
+//! the client socket's TCP_INFO. The server sends no more than its 8 KiB
+//! write buffer at a time, which always fits in one loopback segment, so
+//! each flush is at most one segment.
+//!
+//! Every scenario runs twice: against the server as configured by default,
+//! which batches responses, and with response batching turned off, where
+//! every response is flushed on its own.
+//!
+//! Run with `cargo bench --bench pipelining`. TCP_INFO is Linux only, so
+//! elsewhere the benchmark does nothing.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    linux::main();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("the pipelining benchmark needs Linux for TCP_INFO");
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use prime_time::{serve, Limits, PrimeCache, ServerConfig};
+    use std::{
+        io::{BufRead, BufReader, Write},
+        mem,
+        net::{SocketAddr, TcpListener, TcpStream},
+        os::fd::AsRawFd,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Instant,
+    };
+
+    const REQUESTS: usize = 200_000;
+
+    pub fn main() {
+        for batch_responses in [true, false] {
+            let addr = start_server(batch_responses);
+
+            println!(
+                "response batching {}",
+                if batch_responses { "on" } else { "off" }
+            );
+            println!(
+                "{:>8} {:>12} {:>14} {:>18}",
+                "window", "requests/s", "segments", "segments per response"
+            );
+            for window in [1, 16, 256, REQUESTS] {
+                run(addr, window);
+            }
+            println!();
+        }
+    }
+
+    fn start_server(batch_responses: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind an ephemeral port");
+        let addr = listener.local_addr().expect("listener has an address");
+
+        let mut config = ServerConfig::new(addr.to_string());
+        config.batch_responses = batch_responses;
+        let cache = PrimeCache::new(config.sieve_bound, config.cache_capacity);
+        let limits = Limits::new(&config);
+        thread::spawn(move || serve(listener, config, cache, limits));
+
+        addr
+    }
+
+    fn run(addr: SocketAddr, window: usize) {
+        let stream = TcpStream::connect(addr).expect("connect to the server");
+        stream.set_nodelay(true).expect("disable Nagle");
+        let mut writer = stream.try_clone().expect("clone the stream");
+        let mut reader = BufReader::new(stream);
+
+        // Large numbers miss the sieve, so every request reaches a worker
+        let mut payload = Vec::new();
+        let mut offsets = vec![0];
+        for i in 0..REQUESTS {
+            let number = u64::MAX - i as u64;
+            payload.extend(format!("{{\"method\":\"isPrime\",\"number\":{}}}\n", number).bytes());
+            offsets.push(payload.len());
+        }
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let segments_before = data_segments_in(reader.get_ref());
+        let started = Instant::now();
+
+        // The sender tops the window up whenever responses free some of it
+        let sender = {
+            let received = Arc::clone(&received);
+            thread::spawn(move || {
+                let mut sent = 0;
+                while sent < REQUESTS {
+                    let outstanding = sent - received.load(Ordering::Acquire);
+                    if outstanding >= window {
+                        thread::yield_now();
+                        continue;
+                    }
+
+                    let end = (sent + window - outstanding).min(REQUESTS);
+                    let mut batch = &payload[offsets[sent]..offsets[end]];
+                    while !batch.is_empty() {
+                        let written = writer.write(batch).expect("send requests");
+                        batch = &batch[written..];
+                    }
+                    sent = end;
+                }
+            })
+        };
+
+        let mut line = String::new();
+        for _ in 0..REQUESTS {
+            line.clear();
+            reader.read_line(&mut line).expect("read a response");
+            assert!(
+                line.starts_with("{\"method\":\"isPrime\""),
+                "got {:?}",
+                line
+            );
+            received.fetch_add(1, Ordering::Release);
+        }
+
+        let elapsed = started.elapsed();
+        sender.join().expect("sender finished");
+        let segments = data_segments_in(reader.get_ref()) - segments_before;
+
+        println!(
+            "{:>8} {:>12.0} {:>14} {:>18.3}",
+            window,
+            REQUESTS as f64 / elapsed.as_secs_f64(),
+            segments,
+            segments as f64 / REQUESTS as f64
+        );
+    }
+
+    // Segments carrying data that this socket has received so far
+    fn data_segments_in(stream: &TcpStream) -> u32 {
+        let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+        let mut length = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        assert_eq!(result, 0, "getsockopt(TCP_INFO) failed");
+        info.tcpi_data_segs_in
+    }
+}
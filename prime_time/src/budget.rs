@@ -0,0 +1,31 @@
+use std::time::{Duration, Instant};
+
+/// The time a single request may spend being evaluated. The long-running
+/// computations check it as they go and give up once it's spent, so a worker
+/// is never stuck on a request nobody is willing to wait for.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    // None when the request may run for as long as it needs
+    deadline: Option<Instant>,
+}
+
+/// The request ran out of budget and its evaluation was abandoned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeout;
+
+impl Budget {
+    /// A budget that starts counting down now.
+    pub fn new(limit: Option<Duration>) -> Self {
+        Self {
+            // A limit too large to represent is as good as no limit
+            deadline: limit.and_then(|limit| Instant::now().checked_add(limit)),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), Timeout> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Timeout),
+            _ => Ok(()),
+        }
+    }
+}
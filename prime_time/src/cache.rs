@@ -0,0 +1,231 @@
+use crate::budget::{Budget, Timeout};
+use crate::primality::is_prime_integer;
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Keys can run to thousands of digits, so the cache is bounded by the bytes
+// its keys hold as well as by its entry count
+const MAX_KEY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Process-wide primality answers shared by every connection. Numbers below
+/// the sieve bound are looked up in a precomputed bitmap, and everything else
+/// goes through a bounded LRU cache in front of the real test.
+#[derive(Clone)]
+pub struct PrimeCache {
+    inner: Arc<CacheInner>,
+}
+
+struct CacheInner {
+    sieve: PrimeBitmap,
+    lru: Mutex<LruCache>,
+    sieve_hits: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct CacheStats {
+    pub sieve_hits: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl PrimeCache {
+    pub fn new(sieve_bound: u64, capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(CacheInner {
+                sieve: PrimeBitmap::new(sieve_bound),
+                lru: Mutex::new(LruCache::new(capacity)),
+                sieve_hits: AtomicU64::new(0),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn is_prime(&self, num: &BigInt, budget: &Budget) -> Result<bool, Timeout> {
+        if let Some(prime) = num.to_u64().and_then(|num| self.inner.sieve.get(num)) {
+            self.inner.sieve_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(prime);
+        }
+
+        if let Some(prime) = self.inner.lru.lock().get(num) {
+            self.inner.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(prime);
+        }
+
+        // Run the test without holding the lock so other connections can
+        // keep using the cache in the meantime
+        self.inner.misses.fetch_add(1, Ordering::Relaxed);
+        // An abandoned test has no answer worth caching
+        let prime = is_prime_integer(num, budget)?;
+        self.inner.lru.lock().insert(num.clone(), prime);
+        Ok(prime)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            sieve_hits: self.inner.sieve_hits.load(Ordering::Relaxed),
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// One bit per odd number below the bound, set when that number is prime
+struct PrimeBitmap {
+    bound: u64,
+    bits: Vec<u64>,
+}
+
+impl PrimeBitmap {
+    fn new(bound: u64) -> Self {
+        // Bit i stands for the odd number 2i + 1, and starts out set
+        let odd_count = bound.div_ceil(2);
+        let mut bitmap = Self {
+            bound,
+            bits: vec![u64::MAX; odd_count.div_ceil(64) as usize],
+        };
+
+        // 1 isn't prime
+        if bound > 1 {
+            bitmap.clear(1);
+        }
+
+        let mut num = 3;
+        while num * num < bound {
+            if bitmap.is_set(num) {
+                for multiple in (num * num..bound).step_by(2 * num as usize) {
+                    bitmap.clear(multiple);
+                }
+            }
+            num += 2;
+        }
+
+        bitmap
+    }
+
+    fn is_set(&self, odd: u64) -> bool {
+        let index = odd / 2;
+        self.bits[(index / 64) as usize] & (1 << (index % 64)) != 0
+    }
+
+    fn clear(&mut self, odd: u64) {
+        let index = odd / 2;
+        self.bits[(index / 64) as usize] &= !(1 << (index % 64));
+    }
+
+    // None when num is past the bound and has to be tested some other way
+    fn get(&self, num: u64) -> Option<bool> {
+        if num >= self.bound {
+            return None;
+        }
+
+        match num {
+            2 => Some(true),
+            _ if num.is_multiple_of(2) => Some(false),
+            _ => Some(self.is_set(num)),
+        }
+    }
+}
+
+// A least-recently-used map from number to primality. Recency is tracked with
+// a counter, and the oldest entry is the smallest key in `by_age`.
+struct LruCache {
+    capacity: usize,
+    // Bytes held by the keys of every entry, against MAX_KEY_BYTES
+    key_bytes: usize,
+    clock: u64,
+    entries: HashMap<BigInt, (bool, u64)>,
+    by_age: BTreeMap<u64, BigInt>,
+}
+
+impl LruCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            key_bytes: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            by_age: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, num: &BigInt) -> Option<bool> {
+        self.clock += 1;
+        let (prime, age) = self.entries.get_mut(num)?;
+
+        // Move the entry to the young end
+        let key = self.by_age.remove(age)?;
+        *age = self.clock;
+        self.by_age.insert(self.clock, key);
+
+        Some(*prime)
+    }
+
+    fn insert(&mut self, num: BigInt, prime: bool) {
+        if self.capacity == 0 || self.entries.contains_key(&num) {
+            return;
+        }
+
+        let bytes = key_bytes(&num);
+        while self.entries.len() >= self.capacity || self.key_bytes + bytes > MAX_KEY_BYTES {
+            let Some((_, oldest)) = self.by_age.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            self.key_bytes -= key_bytes(&oldest);
+        }
+
+        self.key_bytes += bytes;
+        self.clock += 1;
+        self.by_age.insert(self.clock, num.clone());
+        self.entries.insert(num, (prime, self.clock));
+    }
+}
+
+// Each key is stored twice, once in `entries` and once in `by_age`
+fn key_bytes(num: &BigInt) -> usize {
+    2 * num.bits().div_ceil(8) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::One;
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let mut lru = LruCache::new(2);
+        lru.insert(BigInt::from(7), true);
+        lru.insert(BigInt::from(9), false);
+        assert_eq!(lru.get(&BigInt::from(7)), Some(true));
+
+        lru.insert(BigInt::from(11), true);
+        assert_eq!(lru.get(&BigInt::from(9)), None);
+        assert_eq!(lru.get(&BigInt::from(7)), Some(true));
+        assert_eq!(lru.get(&BigInt::from(11)), Some(true));
+    }
+
+    #[test]
+    fn huge_keys_are_bounded_by_bytes() {
+        let mut lru = LruCache::new(100_000);
+        // 2^13600 + i, about as large as a request can make them
+        let base = BigInt::one() << 13_600;
+        for i in 0..10_000 {
+            lru.insert(&base + i, false);
+            assert!(lru.key_bytes <= MAX_KEY_BYTES);
+        }
+
+        let held: usize = lru.entries.keys().map(key_bytes).sum();
+        assert_eq!(held, lru.key_bytes);
+        assert!(lru.entries.len() < 10_000);
+        // The newest entry survives, the oldest has gone
+        assert_eq!(lru.get(&(&base + 9_999)), Some(false));
+        assert_eq!(lru.get(&base), None);
+    }
+}
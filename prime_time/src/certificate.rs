@@ -0,0 +1,183 @@
+use crate::budget::{Budget, Timeout};
+use crate::factor::factorize;
+use crate::number::integer_to_number;
+use crate::primality::{is_prime, pow_mod, pow_mod_big, SMALL_PRIMES};
+use num_bigint::{BigInt, BigUint, Sign};
+use num_integer::Integer;
+use num_traits::{One, ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+use serde_json::Number;
+
+// Fermat bases we'll try on a big composite before giving up on a certificate
+const MAX_FERMAT_BASE: u32 = 1000;
+
+/// Evidence for an isPrime answer that can be checked without trusting
+/// `is_prime`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Certificate {
+    /// `n` is prime because `witness` has multiplicative order n - 1 modulo n.
+    /// `factors` certifies every distinct prime factor of n - 1 in turn.
+    Pratt {
+        n: u64,
+        witness: u64,
+        factors: Vec<Certificate>,
+    },
+
+    /// The number is composite because `factor` divides it.
+    Factor { factor: Number },
+
+    /// The number is composite because witness^(n-1) != 1 (mod n).
+    FermatWitness { witness: u64 },
+}
+
+/// Builds a certificate for num, or None if we can't produce one cheaply.
+/// Primes are only certified up to u64::MAX, since Pratt certificates need
+/// n - 1 fully factored. Past that, `prime` is the answer already worked out
+/// for num, so that a big prime isn't put through the search for a witness
+/// of compositeness that can't succeed.
+pub fn certify(num: &BigInt, prime: bool, budget: &Budget) -> Result<Option<Certificate>, Timeout> {
+    // Only naturals above one are prime or composite
+    if num.sign() != Sign::Plus || num.is_one() {
+        return Ok(None);
+    }
+
+    match num.to_u64() {
+        Some(num) if is_prime(num) => Ok(Some(pratt_certificate(num))),
+        Some(num) => Ok(Some(Certificate::Factor {
+            factor: Number::from(factorize(num)[0]),
+        })),
+        None if prime => Ok(None),
+        None => composite_certificate(num.magnitude(), budget),
+    }
+}
+
+fn pratt_certificate(prime: u64) -> Certificate {
+    if prime == 2 {
+        return Certificate::Pratt {
+            n: 2,
+            witness: 1,
+            factors: Vec::new(),
+        };
+    }
+
+    let mut distinct_factors = factorize(prime - 1);
+    distinct_factors.dedup();
+
+    // Any primitive root works, and there's always a small one
+    let witness = (2..prime)
+        .find(|&candidate| {
+            distinct_factors
+                .iter()
+                .all(|factor| pow_mod(candidate, (prime - 1) / factor, prime) != 1)
+        })
+        .expect("every prime has a primitive root");
+
+    Certificate::Pratt {
+        n: prime,
+        witness,
+        factors: distinct_factors
+            .into_iter()
+            .map(pratt_certificate)
+            .collect(),
+    }
+}
+
+fn composite_certificate(num: &BigUint, budget: &Budget) -> Result<Option<Certificate>, Timeout> {
+    for prime in SMALL_PRIMES {
+        if (num % prime).is_zero() {
+            return Ok(Some(Certificate::Factor {
+                factor: Number::from(prime),
+            }));
+        }
+    }
+
+    let exponent = num - 1u32;
+    for base in 2..=MAX_FERMAT_BASE {
+        let base = BigUint::from(base);
+        let shared = base.gcd(num);
+        if !shared.is_one() {
+            return Ok(Some(Certificate::Factor {
+                factor: integer_to_number(&shared),
+            }));
+        }
+
+        if !pow_mod_big(&base, &exponent, num, budget)?.is_one() {
+            return Ok(base
+                .to_u64()
+                .map(|witness| Certificate::FermatWitness { witness }));
+        }
+    }
+
+    // Carmichael numbers with only large factors have no small Fermat witness
+    Ok(None)
+}
+
+/// Checks a certificate against num independently of the primality tests.
+/// Returns true when the certificate proves its claim about num.
+pub fn verify_certificate(
+    num: &BigInt,
+    certificate: &Certificate,
+    budget: &Budget,
+) -> Result<bool, Timeout> {
+    if num.sign() != Sign::Plus {
+        return Ok(false);
+    }
+    let num = num.magnitude();
+
+    match certificate {
+        Certificate::Pratt { n, .. } => Ok(BigUint::from(*n) == *num && verify_pratt(certificate)),
+        Certificate::Factor { factor } => match factor.as_str().parse::<BigUint>() {
+            Ok(factor) => Ok(factor > BigUint::one() && factor < *num && (num % factor).is_zero()),
+            Err(_) => Ok(false),
+        },
+        Certificate::FermatWitness { witness } => {
+            let witness = BigUint::from(*witness);
+            let exponent = num - 1u32;
+            Ok(witness > BigUint::one()
+                && witness < exponent
+                && !pow_mod_big(&witness, &exponent, num, budget)?.is_one())
+        }
+    }
+}
+
+fn verify_pratt(certificate: &Certificate) -> bool {
+    let Certificate::Pratt {
+        n,
+        witness,
+        factors,
+    } = certificate
+    else {
+        return false;
+    };
+    let (n, witness) = (*n, *witness);
+
+    if n == 2 {
+        return factors.is_empty();
+    }
+    if n < 2 || witness == 0 || witness >= n || pow_mod(witness, n - 1, n) != 1 {
+        return false;
+    }
+
+    // The listed primes must account for all of n - 1, and the witness must
+    // not have a smaller order dividing (n - 1) / q for any of them
+    let mut remaining = n - 1;
+    for factor in factors {
+        let Certificate::Pratt { n: prime, .. } = factor else {
+            return false;
+        };
+        let prime = *prime;
+
+        if prime < 2 || !remaining.is_multiple_of(prime) || !verify_pratt(factor) {
+            return false;
+        }
+        while remaining.is_multiple_of(prime) {
+            remaining /= prime;
+        }
+        if pow_mod(witness, (n - 1) / prime, n) == 1 {
+            return false;
+        }
+    }
+
+    remaining == 1
+}
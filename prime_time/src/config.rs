@@ -0,0 +1,263 @@
+use crate::framing::Framing;
+use std::{thread, time::Duration};
+
+// Defaults chosen to comfortably fit any legitimate request while still
+// protecting the server from runaway clients
+const DEFAULT_MAX_LINE_BYTES: usize = 1024 * 1024;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_WRITE_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_RANGE_WIDTH: u64 = 10_000_000;
+const DEFAULT_MAX_BATCH: usize = 100;
+const DEFAULT_REQUEST_BUDGET_MS: u64 = 10_000;
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+const DEFAULT_SIEVE_BOUND: u64 = 10_000_000;
+const DEFAULT_CACHE_CAPACITY: usize = 100_000;
+const DEFAULT_RATE_BURST: u32 = 100;
+const DEFAULT_MAX_ACTIVE: usize = 256;
+
+/// The wire protocol spoken on the TCP listener.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    // The line-delimited protocol from the problem statement
+    Native,
+    // Line-delimited JSON-RPC 2.0 requests and batches
+    JsonRpc,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    // The address the TCP listener binds to
+    pub addr: String,
+
+    pub protocol: Protocol,
+
+    // The framing every native connection uses. None lets each client pick
+    // with a preamble byte, falling back to JSON lines.
+    pub framing: Option<Framing>,
+
+    // Explain malformed requests with a JSON error object instead of the
+    // bare malformed response the spec asks for
+    pub diagnostic_errors: bool,
+
+    // Where the optional HTTP listener binds, on the same interface
+    pub http_addr: Option<String>,
+
+    // Hold responses back until the reader runs out of buffered requests, so
+    // a pipelined burst goes out in one write. Off, every response is
+    // flushed on its own, which is only useful for comparison.
+    pub batch_responses: bool,
+
+    // Longest request line we'll buffer, including the trailing newline
+    pub max_line_bytes: usize,
+
+    // How long a read may wait on the client before the connection is
+    // dropped. This applies between requests as well as within one, so a
+    // client that goes quiet for this long is disconnected. None disables
+    // the timeout.
+    pub read_timeout: Option<Duration>,
+
+    // How long a write may wait for the client to make room before the
+    // connection is dropped, so a client that stops reading can't hold its
+    // requests in flight forever. None disables the timeout.
+    pub write_timeout: Option<Duration>,
+
+    // Widest [from, to] span a primesInRange request may cover
+    pub max_range_width: u64,
+
+    // Most requests a single JSON-RPC batch may hold
+    pub max_batch: usize,
+
+    // How long a single request may compute before it's cancelled and
+    // answered with a timeout error. None lets requests run to completion.
+    pub request_budget: Option<Duration>,
+
+    // Threads in the shared pool that evaluates requests
+    pub workers: usize,
+
+    // Requests a single connection may have queued or evaluating at once
+    pub max_in_flight: usize,
+
+    // Every number below this bound is answered from a precomputed sieve
+    pub sieve_bound: u64,
+
+    // Entries kept in the LRU cache of primality answers. Whatever this is,
+    // the cached numbers themselves are held to 16 MiB.
+    pub cache_capacity: usize,
+
+    // Requests per second each peer IP may sustain. None turns the per-peer
+    // limit off.
+    pub rate_limit: Option<u32>,
+
+    // Requests a peer may send in a burst on top of the sustained rate
+    pub rate_burst: u32,
+
+    // Computations that may be queued or running at once, across every client
+    pub max_active: usize,
+}
+
+impl ServerConfig {
+    /// The default configuration for a TCP listener on addr.
+    pub fn new(addr: String) -> Self {
+        Self {
+            addr,
+            protocol: Protocol::Native,
+            framing: None,
+            diagnostic_errors: false,
+            http_addr: None,
+            batch_responses: true,
+            max_line_bytes: DEFAULT_MAX_LINE_BYTES,
+            read_timeout: Some(Duration::from_secs(DEFAULT_READ_TIMEOUT_SECS)),
+            write_timeout: Some(Duration::from_secs(DEFAULT_WRITE_TIMEOUT_SECS)),
+            max_range_width: DEFAULT_MAX_RANGE_WIDTH,
+            max_batch: DEFAULT_MAX_BATCH,
+            request_budget: Some(Duration::from_millis(DEFAULT_REQUEST_BUDGET_MS)),
+            workers: thread::available_parallelism().map_or(4, |workers| workers.get()),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            sieve_bound: DEFAULT_SIEVE_BOUND,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            rate_limit: None,
+            rate_burst: DEFAULT_RATE_BURST,
+            max_active: DEFAULT_MAX_ACTIVE,
+        }
+    }
+
+    /// Parses `<ipv4_address> <port> [--flag value]...` from the command line.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        if args.len() < 3 {
+            return Err(format!(
+                "usage: {} <ipv4_address> <port> [options]",
+                args.first().map(String::as_str).unwrap_or("prime_time")
+            ));
+        }
+
+        let mut config = Self::new(format!("{}:{}", args[1], args[2]));
+
+        let mut options = args[3..].iter();
+        while let Some(flag) = options.next() {
+            // Switches that don't take a value
+            if flag == "--diagnostic-errors" {
+                config.diagnostic_errors = true;
+                continue;
+            }
+            if flag == "--no-response-batching" {
+                config.batch_responses = false;
+                continue;
+            }
+
+            let value = options
+                .next()
+                .ok_or_else(|| format!("missing value for {}", flag))?;
+
+            match flag.as_str() {
+                "--protocol" => {
+                    config.protocol = match value.as_str() {
+                        "native" => Protocol::Native,
+                        "jsonrpc" => Protocol::JsonRpc,
+                        _ => return Err(format!("invalid value for {}: {}", flag, value)),
+                    };
+                }
+                "--framing" => {
+                    config.framing = match value.as_str() {
+                        "auto" => None,
+                        "json" => Some(Framing::Json),
+                        "msgpack" => Some(Framing::MessagePack),
+                        "cbor" => Some(Framing::Cbor),
+                        _ => return Err(format!("invalid value for {}: {}", flag, value)),
+                    };
+                }
+                "--http-port" => {
+                    let port: u16 = parse_value(flag, value)?;
+                    config.http_addr = Some(format!("{}:{}", args[1], port));
+                }
+                "--max-line-bytes" => {
+                    config.max_line_bytes = parse_value(flag, value)?;
+                    if config.max_line_bytes == 0 {
+                        return Err(format!("{} must be greater than zero", flag));
+                    }
+                }
+                "--read-timeout-secs" => {
+                    // Zero turns the timeout off entirely
+                    config.read_timeout = match parse_value(flag, value)? {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    };
+                }
+                "--write-timeout-secs" => {
+                    // Zero turns the timeout off entirely
+                    config.write_timeout = match parse_value(flag, value)? {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    };
+                }
+                "--max-range-width" => {
+                    config.max_range_width = parse_value(flag, value)?;
+                    if config.max_range_width == 0 {
+                        return Err(format!("{} must be greater than zero", flag));
+                    }
+                }
+                "--max-batch" => {
+                    config.max_batch = parse_value(flag, value)?;
+                    if config.max_batch == 0 {
+                        return Err(format!("{} must be greater than zero", flag));
+                    }
+                }
+                "--request-budget-ms" => {
+                    // Zero turns the budget off entirely
+                    config.request_budget = match parse_value(flag, value)? {
+                        0 => None,
+                        millis => Some(Duration::from_millis(millis)),
+                    };
+                }
+                "--workers" => {
+                    config.workers = parse_value(flag, value)?;
+                    if config.workers == 0 {
+                        return Err(format!("{} must be greater than zero", flag));
+                    }
+                }
+                "--max-in-flight" => {
+                    config.max_in_flight = parse_value(flag, value)?;
+                    if config.max_in_flight == 0 {
+                        return Err(format!("{} must be greater than zero", flag));
+                    }
+                }
+                "--sieve-bound" => config.sieve_bound = parse_value(flag, value)?,
+                "--cache-size" => config.cache_capacity = parse_value(flag, value)?,
+                "--rate-limit" => {
+                    // Zero turns the per-peer limit off entirely
+                    config.rate_limit = match parse_value(flag, value)? {
+                        0 => None,
+                        rate => Some(rate),
+                    };
+                }
+                "--rate-burst" => {
+                    config.rate_burst = parse_value(flag, value)?;
+                    if config.rate_burst == 0 {
+                        return Err(format!("{} must be greater than zero", flag));
+                    }
+                }
+                "--max-active" => {
+                    config.max_active = parse_value(flag, value)?;
+                    if config.max_active == 0 {
+                        return Err(format!("{} must be greater than zero", flag));
+                    }
+                }
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+
+        // JSON-RPC is defined over JSON text, so it has no binary framing
+        if config.protocol == Protocol::JsonRpc
+            && matches!(config.framing, Some(Framing::MessagePack | Framing::Cbor))
+        {
+            return Err("--framing msgpack and cbor only apply to --protocol native".to_owned());
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
@@ -0,0 +1,53 @@
+use crate::budget::{Budget, Timeout};
+
+// Largest input primeCount accepts. Lucy's algorithm runs in O(n^(3/4)), which
+// keeps this bound well under a second.
+pub const MAX_PRIME_COUNT_INPUT: u64 = 100_000_000_000;
+
+/// π(num): how many primes are less than or equal to num, using Lucy
+/// Hedgehog's algorithm.
+pub fn prime_count(num: u64, budget: &Budget) -> Result<u64, Timeout> {
+    if num < 2 {
+        return Ok(0);
+    }
+
+    let root = num.isqrt() as usize;
+
+    // small[v] counts the survivors in 2..=v for v <= root, and large[i] does
+    // the same for num / i. Before sieving every number from 2 up survives.
+    let mut small: Vec<u64> = (0..=root as u64).map(|v| v.saturating_sub(1)).collect();
+    let mut large: Vec<u64> = (0..=root as u64)
+        .map(|i| num.checked_div(i).map_or(0, |value| value - 1))
+        .collect();
+
+    for prime in 2..=root {
+        // Only primes change the count between prime - 1 and prime
+        if small[prime] == small[prime - 1] {
+            continue;
+        }
+        budget.check()?;
+
+        let primes_below = small[prime - 1];
+        let square = (prime * prime) as u64;
+
+        // Remove the survivors whose smallest prime factor is `prime`
+        for i in 1..=root {
+            let value = num / i as u64;
+            if value < square {
+                break;
+            }
+
+            let quotient_count = match i * prime {
+                index if index <= root => large[index],
+                _ => small[(value / prime as u64) as usize],
+            };
+            large[i] -= quotient_count - primes_below;
+        }
+
+        for value in (square as usize..=root).rev() {
+            small[value] -= small[value / prime] - primes_below;
+        }
+    }
+
+    Ok(large[1])
+}
@@ -0,0 +1,99 @@
+use crate::primality::{is_prime, mul_mod, SMALL_PRIMES};
+use num_integer::Integer;
+
+// Number of Pollard-Brent steps folded into a single gcd
+const BATCH_SIZE: u64 = 128;
+
+/// Prime factors of num in ascending order, repeated by multiplicity.
+pub fn factorize(mut num: u64) -> Vec<u64> {
+    let mut factors = Vec::new();
+
+    // Strip the small primes first so Pollard's rho only sees hard cases
+    for prime in SMALL_PRIMES {
+        while num.is_multiple_of(prime) {
+            factors.push(prime);
+            num /= prime;
+        }
+    }
+
+    let mut remaining = Vec::new();
+    if num > 1 {
+        remaining.push(num);
+    }
+
+    while let Some(composite) = remaining.pop() {
+        if is_prime(composite) {
+            factors.push(composite);
+            continue;
+        }
+
+        let divisor = find_divisor(composite);
+        remaining.push(divisor);
+        remaining.push(composite / divisor);
+    }
+
+    factors.sort_unstable();
+    factors
+}
+
+// Finds a nontrivial divisor of an odd composite with no small factors
+fn find_divisor(num: u64) -> u64 {
+    // A perfect square defeats some rho sequences, so check for it directly
+    let root = num.isqrt();
+    if root * root == num {
+        return root;
+    }
+
+    (1..)
+        .find_map(|increment| pollard_brent(num, increment))
+        .expect("Pollard's rho always finds a divisor of a composite eventually")
+}
+
+// Brent's variant of Pollard's rho using x -> x^2 + increment (mod num)
+fn pollard_brent(num: u64, increment: u64) -> Option<u64> {
+    let step = |x: u64| (mul_mod(x, x, num) as u128 + increment as u128) as u64 % num;
+
+    let mut y = 2;
+    let mut x = y;
+    let mut saved = y;
+    let mut product = 1;
+    let mut divisor = 1;
+    let mut cycle_length = 1;
+
+    while divisor == 1 {
+        x = y;
+        for _ in 0..cycle_length {
+            y = step(y);
+        }
+
+        let mut steps = 0;
+        while steps < cycle_length && divisor == 1 {
+            saved = y;
+            for _ in 0..BATCH_SIZE.min(cycle_length - steps) {
+                y = step(y);
+                product = mul_mod(product, x.abs_diff(y), num);
+            }
+            divisor = product.gcd(&num);
+            steps += BATCH_SIZE;
+        }
+
+        cycle_length *= 2;
+    }
+
+    // The batch overshot, so replay it one step at a time
+    if divisor == num {
+        loop {
+            saved = step(saved);
+            divisor = x.abs_diff(saved).gcd(&num);
+            if divisor > 1 {
+                break;
+            }
+        }
+    }
+
+    if divisor == num {
+        None
+    } else {
+        Some(divisor)
+    }
+}
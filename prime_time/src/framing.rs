@@ -0,0 +1,236 @@
+use crate::config::ServerConfig;
+use crate::methods::{method_params, parse_request, parse_request_value, MethodCall, RequestError};
+use serde::{Serialize, Serializer};
+use serde_json::{Number, Value};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+};
+
+// A client that wants a binary framing sends one of these bytes before its
+// first request. Neither can start a line of JSON.
+const MESSAGE_PACK_PREAMBLE: u8 = 0x01;
+const CBOR_PREAMBLE: u8 = 0x02;
+
+/// How requests and responses are delimited and encoded on a connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    // Newline-delimited JSON, as in the problem statement
+    Json,
+    // A 4-byte big-endian length followed by a MessagePack document
+    MessagePack,
+    // A 4-byte big-endian length followed by a CBOR document
+    Cbor,
+}
+
+/// What came off the wire for a single request.
+pub enum ReadOutcome {
+    Request(Vec<u8>),
+    TooLong,
+    Eof,
+}
+
+/// Works out the framing of a new connection from its preamble byte, if it
+/// sent one. Anything else is left in the reader as the start of a JSON line.
+pub fn negotiate(reader: &mut BufReader<TcpStream>) -> io::Result<Framing> {
+    let framing = match reader.fill_buf()?.first() {
+        Some(&MESSAGE_PACK_PREAMBLE) => Framing::MessagePack,
+        Some(&CBOR_PREAMBLE) => Framing::Cbor,
+        _ => return Ok(Framing::Json),
+    };
+
+    reader.consume(1);
+    Ok(framing)
+}
+
+/// Whether a whole request is already sitting in the reader's buffer, so the
+/// next read can be answered without waiting on the client.
+pub fn has_buffered_request(reader: &BufReader<TcpStream>, framing: Framing) -> bool {
+    let buffered = reader.buffer();
+    match framing {
+        Framing::Json => buffered.contains(&b'\n'),
+        Framing::MessagePack | Framing::Cbor => match buffered.first_chunk::<4>() {
+            Some(length) => buffered.len() - 4 >= u32::from_be_bytes(*length) as usize,
+            None => false,
+        },
+    }
+}
+
+/// Reads the next request without ever buffering more than max_bytes of it.
+pub fn read_request(
+    reader: &mut BufReader<TcpStream>,
+    framing: Framing,
+    max_bytes: usize,
+) -> io::Result<ReadOutcome> {
+    match framing {
+        Framing::Json => read_line(reader, max_bytes),
+        Framing::MessagePack | Framing::Cbor => read_frame(reader, max_bytes),
+    }
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>, max_bytes: usize) -> io::Result<ReadOutcome> {
+    let mut read_buffer = Vec::new();
+    let read = reader
+        .by_ref()
+        .take(max_bytes as u64)
+        .read_until(b'\n', &mut read_buffer)?;
+
+    if read == 0 {
+        return Ok(ReadOutcome::Eof);
+    }
+
+    // Hitting the limit without a newline means the line is too long. A short
+    // read without one is just the client's last, unterminated line.
+    if read == max_bytes && read_buffer.last() != Some(&b'\n') {
+        return Ok(ReadOutcome::TooLong);
+    }
+
+    Ok(ReadOutcome::Request(read_buffer))
+}
+
+fn read_frame(reader: &mut BufReader<TcpStream>, max_bytes: usize) -> io::Result<ReadOutcome> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        // A clean close between frames
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(ReadOutcome::Eof),
+        Err(err) => return Err(err),
+    }
+
+    // The length is checked before anything is allocated for the payload
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max_bytes {
+        return Ok(ReadOutcome::TooLong);
+    }
+
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    Ok(ReadOutcome::Request(payload))
+}
+
+/// Decodes and validates one request in the connection's framing. Binary
+/// framings may send any numeric field as a decimal integer string, the way
+/// responses carry integers wider than 64 bits. JSON lines may not.
+pub fn decode_request(
+    framing: Framing,
+    payload: Vec<u8>,
+    config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    let mut value: Value = match framing {
+        Framing::Json => {
+            let line = String::from_utf8(payload).map_err(|_| RequestError::InvalidUtf8)?;
+            return parse_request(&line, config);
+        }
+        Framing::MessagePack => rmp_serde::from_slice(&payload)
+            .map_err(|err| RequestError::InvalidFrame(err.to_string()))?,
+        Framing::Cbor => ciborium::from_reader(payload.as_slice())
+            .map_err(|err| RequestError::InvalidFrame(err.to_string()))?,
+    };
+
+    accept_decimal_strings(&mut value);
+    parse_request_value(value, config)
+}
+
+// Swaps decimal integer strings in the method's numeric fields for numbers.
+// Every field in the method registry is numeric.
+fn accept_decimal_strings(value: &mut Value) {
+    let Value::Object(fields) = value else {
+        return;
+    };
+    let Some(params) = fields
+        .get("method")
+        .and_then(Value::as_str)
+        .and_then(method_params)
+    else {
+        return;
+    };
+
+    for param in params {
+        let Some(field) = fields.get_mut(*param) else {
+            continue;
+        };
+        if let Some(number) = field.as_str().and_then(decimal_integer) {
+            *field = Value::Number(number);
+        }
+    }
+}
+
+// An optional minus sign and at least one digit, nothing else
+fn decimal_integer(text: &str) -> Option<Number> {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    // With arbitrary precision enabled any integer text is a valid Number
+    serde_json::from_str(text).ok()
+}
+
+/// Writes one message in the connection's framing.
+pub fn write_message<W: Write, T: Serialize>(
+    mut writer: W,
+    framing: Framing,
+    message: &T,
+) -> io::Result<()> {
+    if framing == Framing::Json {
+        serde_json::to_writer(&mut writer, message)?;
+        return writer.write_all(b"\n");
+    }
+
+    // Going through a Value first lets numbers be re-encoded natively
+    let value = serde_json::to_value(message)?;
+    let payload = match framing {
+        Framing::MessagePack => rmp_serde::to_vec_named(&Portable(&value))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        _ => {
+            let mut payload = Vec::new();
+            ciborium::into_writer(&Portable(&value), &mut payload)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            payload
+        }
+    };
+
+    write_frame(writer, &payload)
+}
+
+/// The bare malformed response for a binary framing: an empty frame.
+pub fn write_empty_frame<W: Write>(writer: W) -> io::Result<()> {
+    write_frame(writer, &[])
+}
+
+fn write_frame<W: Write>(mut writer: W, payload: &[u8]) -> io::Result<()> {
+    let length = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "frame is too large"))?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+// Serializes a JSON value with its numbers as native integers. With arbitrary
+// precision on, serde_json would otherwise hand binary formats a private
+// wrapper struct. Integers wider than 64 bits travel as their decimal text,
+// which decode_request accepts back.
+struct Portable<'a>(&'a Value);
+
+impl Serialize for Portable<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Null => serializer.serialize_unit(),
+            Value::Bool(value) => serializer.serialize_bool(*value),
+            Value::Number(number) => {
+                if let Some(number) = number.as_u64() {
+                    serializer.serialize_u64(number)
+                } else if let Some(number) = number.as_i64() {
+                    serializer.serialize_i64(number)
+                } else {
+                    serializer.serialize_str(number.as_str())
+                }
+            }
+            Value::String(value) => serializer.serialize_str(value),
+            Value::Array(values) => serializer.collect_seq(values.iter().map(Portable)),
+            Value::Object(fields) => serializer.collect_map(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), Portable(value))),
+            ),
+        }
+    }
+}
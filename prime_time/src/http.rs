@@ -0,0 +1,311 @@
+use crate::budget::{Budget, Timeout};
+use crate::cache::PrimeCache;
+use crate::config::ServerConfig;
+use crate::framing::{write_message, Framing};
+use crate::limits::{ComputePermit, Limits, Rejection};
+use crate::methods::{
+    handle_request, method_params, parse_request, respond_success, timeout_response,
+    MethodResponse, RequestError, StreamDone,
+};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+// Request line plus headers. Anything bigger is not a client we want.
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+/// A minimal HTTP/1.1 front end: `POST /<method>` with the same JSON body the
+/// line protocol takes. Every connection serves a single request.
+pub fn serve_http(listener: TcpListener, config: ServerConfig, cache: PrimeCache, limits: Limits) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let config = config.clone();
+                let cache = cache.clone();
+                let limits = limits.clone();
+                thread::spawn(move || handle_http_connection(stream, config, cache, limits));
+            }
+            Err(err) => println!(
+                "ERROR - Failure while listening to incoming HTTP connections: {}",
+                err
+            ),
+        }
+    }
+}
+
+// What went wrong with an HTTP request, mapped onto a status code
+enum HttpError {
+    BadRequest(String),
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    LengthRequired,
+    // The request ran out of its computation budget
+    Timeout,
+    // Turned away by the rate limit or the concurrency cap
+    Rejected(Rejection),
+}
+
+fn handle_http_connection(
+    stream: TcpStream,
+    config: ServerConfig,
+    cache: PrimeCache,
+    limits: Limits,
+) {
+    let peer_addr = stream.peer_addr().ok();
+    let peer = match peer_addr {
+        Some(addr) => addr.to_string(),
+        None => "unknown-peer".to_owned(),
+    };
+    let peer_ip = peer_addr.map(|addr| addr.ip());
+
+    if let Err(err) = stream.set_read_timeout(config.read_timeout) {
+        println!("{} - ERROR - Failed to set read timeout: {}", peer, err);
+        return;
+    }
+    if let Err(err) = stream.set_write_timeout(config.write_timeout) {
+        println!("{} - ERROR - Failed to set write timeout: {}", peer, err);
+        return;
+    }
+
+    let result = match read_http_request(&stream, &config) {
+        Ok(Ok((path, body))) => evaluate(&path, &body, &config, &cache, &limits, peer_ip),
+        Ok(Err(err)) => Err(err),
+        Err(err) => {
+            println!("{} - ERROR - Failed to read HTTP request: {}", peer, err);
+            return;
+        }
+    };
+
+    let write_result = match result {
+        // A primesInRange stream sieves as it's written, so the permit is
+        // only given back once the body is out
+        Ok((response, _compute)) => write_ok(&stream, &peer, response),
+        Err(err) => respond_error(&stream, &peer, err),
+    };
+
+    if let Err(err) = write_result {
+        println!("{} - ERROR - Failed to write HTTP response: {}", peer, err);
+    }
+}
+
+fn respond_error(stream: &TcpStream, peer: &str, err: HttpError) -> io::Result<()> {
+    // Only rejections come with a hint for when to try again
+    let retry_after = match &err {
+        HttpError::Rejected(rejection) => Some(rejection.retry_after()),
+        _ => None,
+    };
+    let (status, reason) = match err {
+        HttpError::BadRequest(reason) => ("400 Bad Request", reason),
+        HttpError::NotFound => ("404 Not Found", "unknown path".to_owned()),
+        HttpError::MethodNotAllowed => (
+            "405 Method Not Allowed",
+            "only POST is supported".to_owned(),
+        ),
+        HttpError::PayloadTooLarge => (
+            "413 Payload Too Large",
+            "request body is too large".to_owned(),
+        ),
+        HttpError::LengthRequired => (
+            "411 Length Required",
+            "Content-Length is required".to_owned(),
+        ),
+        HttpError::Timeout => (
+            "503 Service Unavailable",
+            "request exceeded its time budget".to_owned(),
+        ),
+        HttpError::Rejected(rejection @ Rejection::RateLimited { .. }) => {
+            ("429 Too Many Requests", rejection.to_string())
+        }
+        HttpError::Rejected(rejection @ Rejection::Overloaded) => {
+            ("503 Service Unavailable", rejection.to_string())
+        }
+    };
+    println!("{} - ERROR - HTTP {}: {}", peer, status, reason);
+    write_error(stream, status, &reason, retry_after)
+}
+
+// Reads the request head and body, returning the path and body bytes
+fn read_http_request(
+    stream: &TcpStream,
+    config: &ServerConfig,
+) -> io::Result<Result<(String, Vec<u8>), HttpError>> {
+    let mut reader = BufReader::new(stream);
+    let mut head = reader.by_ref().take(MAX_HEADER_BYTES as u64);
+
+    let mut request_line = String::new();
+    head.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(verb), Some(path), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(Err(HttpError::BadRequest(
+            "malformed request line".to_owned(),
+        )));
+    };
+
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if head.read_line(&mut header)? == 0 {
+            return Ok(Err(HttpError::BadRequest("incomplete headers".to_owned())));
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                match value.trim().parse::<usize>() {
+                    Ok(length) => content_length = Some(length),
+                    Err(_) => {
+                        return Ok(Err(HttpError::BadRequest(
+                            "invalid Content-Length".to_owned(),
+                        )))
+                    }
+                }
+            }
+        }
+    }
+
+    if verb != "POST" {
+        return Ok(Err(HttpError::MethodNotAllowed));
+    }
+
+    let Some(content_length) = content_length else {
+        return Ok(Err(HttpError::LengthRequired));
+    };
+    if content_length > config.max_line_bytes {
+        return Ok(Err(HttpError::PayloadTooLarge));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Ok((path.to_owned(), body)))
+}
+
+fn evaluate(
+    path: &str,
+    body: &[u8],
+    config: &ServerConfig,
+    cache: &PrimeCache,
+    limits: &Limits,
+    peer_ip: Option<IpAddr>,
+) -> Result<(MethodResponse, ComputePermit), HttpError> {
+    // The path names the method, and it has to agree with the body
+    let method = path.trim_start_matches('/');
+    if method_params(method).is_none() {
+        return Err(HttpError::NotFound);
+    }
+
+    let body = std::str::from_utf8(body)
+        .map_err(|_| HttpError::BadRequest(RequestError::InvalidUtf8.to_string()))?;
+    let request =
+        parse_request(body, config).map_err(|err| HttpError::BadRequest(err.to_string()))?;
+
+    if request.method_name() != method {
+        return Err(HttpError::BadRequest(format!(
+            "body method `{}` doesn't match path",
+            request.method_name()
+        )));
+    }
+
+    let compute = limits.admit(peer_ip).map_err(HttpError::Rejected)?;
+    let budget = Budget::new(config.request_budget);
+    let response = handle_request(request, cache, budget).map_err(|_| HttpError::Timeout)?;
+    Ok((response, compute))
+}
+
+fn write_ok(mut stream: &TcpStream, peer: &str, response: MethodResponse) -> io::Result<()> {
+    let MethodResponse::PrimesInRange(mut chunks) = response else {
+        let mut body = Vec::new();
+        respond_success(&mut body, Framing::Json, response)?;
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )?;
+        return stream.write_all(&body);
+    };
+
+    // Nothing has gone out yet, so a budget that runs out before the first
+    // chunk still gets a proper status
+    let first = match chunks.next() {
+        Some(Err(Timeout)) => return respond_error(stream, peer, HttpError::Timeout),
+        first => first,
+    };
+
+    // Streams come back as NDJSON, exactly as on the line protocol, but
+    // written out as they're sieved rather than gathered up first
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+    )?;
+    let mut body = BufWriter::new(Chunked(stream));
+    for chunk in first.into_iter().chain(chunks) {
+        match chunk {
+            Ok(chunk) => write_message(&mut body, Framing::Json, &chunk)?,
+            Err(Timeout) => {
+                // Too late for a status code. The timeout error is the last
+                // line, and the body is left without its final chunk so the
+                // client can tell it's incomplete.
+                println!("{} - ERROR - HTTP stream exceeded its time budget", peer);
+                write_message(&mut body, Framing::Json, &timeout_response())?;
+                return body.flush();
+            }
+        }
+    }
+    write_message(&mut body, Framing::Json, &StreamDone { done: true })?;
+    body.flush()?;
+    stream.write_all(b"0\r\n\r\n")
+}
+
+// Sends everything written through it as one HTTP chunk per write. Behind a
+// BufWriter that's a chunk per buffer's worth.
+struct Chunked<W: Write>(W);
+
+impl<W: Write> Write for Chunked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.0, "{:X}\r\n", buf.len())?;
+        self.0.write_all(buf)?;
+        self.0.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn write_error(
+    mut stream: &TcpStream,
+    status: &str,
+    reason: &str,
+    retry_after: Option<Duration>,
+) -> io::Result<()> {
+    // Retry-After only has whole seconds, so round up
+    let retry_after = match retry_after {
+        Some(wait) => format!("Retry-After: {}\r\n", wait.as_millis().div_ceil(1000)),
+        None => String::new(),
+    };
+
+    let body = format!("{}\n", reason);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        retry_after,
+        body
+    )
+}
@@ -0,0 +1,291 @@
+use crate::budget::Budget;
+use crate::cache::PrimeCache;
+use crate::config::ServerConfig;
+use crate::limits::{retry_after_ms, Rejection};
+use crate::methods::{
+    handle_request, method_params, parse_call, MethodResponse, PrimesInRangeChunk, RequestError,
+};
+use serde_json::{json, Map, Value};
+
+// Error codes defined by the JSON-RPC 2.0 specification
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// From the range the specification reserves for server errors
+const REQUEST_TIMEOUT: i64 = -32000;
+const REQUEST_REJECTED: i64 = -32001;
+
+/// Parses one line of JSON-RPC 2.0: either a single request or a batch. A
+/// line that can't be evaluated at all gets the error response to send back
+/// instead.
+pub fn parse_rpc_line(line: &[u8], config: &ServerConfig) -> Result<Value, Value> {
+    let value: Value = serde_json::from_slice(line)
+        .map_err(|err| error_response(Value::Null, PARSE_ERROR, err.to_string()))?;
+
+    match &value {
+        Value::Array(batch) if batch.is_empty() => Err(error_response(
+            Value::Null,
+            INVALID_REQUEST,
+            "batch must not be empty".to_owned(),
+        )),
+        Value::Array(batch) if batch.len() > config.max_batch => Err(error_response(
+            Value::Null,
+            INVALID_REQUEST,
+            format!(
+                "batch of {} requests is over the limit of {}",
+                batch.len(),
+                config.max_batch
+            ),
+        )),
+        _ => Ok(value),
+    }
+}
+
+/// How many requests a parsed line holds, which is what admission control
+/// charges it for.
+pub fn rpc_request_count(value: &Value) -> usize {
+    match value {
+        Value::Array(batch) => batch.len(),
+        _ => 1,
+    }
+}
+
+/// Evaluates a line parsed by `parse_rpc_line`. Returns None when nothing
+/// should be written back, which is the case for notifications and batches
+/// made up only of notifications.
+pub fn handle_rpc_line(value: Value, config: &ServerConfig, cache: &PrimeCache) -> Option<Value> {
+    // One budget covers the whole line, so a batch can't hold a worker any
+    // longer than a single request could
+    let budget = Budget::new(config.request_budget);
+
+    match value {
+        Value::Array(batch) => {
+            let responses: Vec<Value> = batch
+                .into_iter()
+                .filter_map(|request| handle_rpc_request(request, config, cache, budget))
+                .collect();
+
+            // A batch of notifications gets no response at all
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Value::Array(responses))
+            }
+        }
+        request => handle_rpc_request(request, config, cache, budget),
+    }
+}
+
+/// The response for a line that never made it to the parser, e.g. because it
+/// was too long.
+pub fn unreadable_line_response(err: &RequestError) -> Value {
+    error_response(Value::Null, PARSE_ERROR, err.to_string())
+}
+
+/// The response for a line turned away by admission control. Nothing in it
+/// was evaluated, so it answers the line as a whole rather than any one id.
+pub fn rejected_response(rejection: &Rejection) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": {
+            "code": REQUEST_REJECTED,
+            "message": rejection.to_string(),
+            "data": { "retryAfterMs": retry_after_ms(rejection.retry_after()) },
+        },
+        "id": Value::Null,
+    })
+}
+
+fn handle_rpc_request(
+    request: Value,
+    config: &ServerConfig,
+    cache: &PrimeCache,
+    budget: Budget,
+) -> Option<Value> {
+    let Value::Object(envelope) = request else {
+        return Some(error_response(
+            Value::Null,
+            INVALID_REQUEST,
+            "request must be a JSON object".to_owned(),
+        ));
+    };
+
+    // Requests without an id are notifications and never get a response
+    let id = match envelope.get("id") {
+        None => None,
+        Some(id @ (Value::Null | Value::Number(_) | Value::String(_))) => Some(id.clone()),
+        Some(_) => {
+            return Some(error_response(
+                Value::Null,
+                INVALID_REQUEST,
+                "`id` must be a string, number or null".to_owned(),
+            ))
+        }
+    };
+
+    match (evaluate(&envelope, config, cache, budget), id) {
+        (Ok(result), Some(id)) => Some(json!({ "jsonrpc": "2.0", "result": result, "id": id })),
+        (Err((code, message)), Some(id)) => Some(error_response(id, code, message)),
+        // A malformed envelope can't be a valid notification, so it's still
+        // reported, just without an id
+        (Err((INVALID_REQUEST, message)), None) => {
+            Some(error_response(Value::Null, INVALID_REQUEST, message))
+        }
+        (_, None) => None,
+    }
+}
+
+fn evaluate(
+    envelope: &Map<String, Value>,
+    config: &ServerConfig,
+    cache: &PrimeCache,
+    budget: Budget,
+) -> Result<Value, (i64, String)> {
+    if envelope.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Err((INVALID_REQUEST, "`jsonrpc` must be \"2.0\"".to_owned()));
+    }
+
+    let Some(method) = envelope.get("method").and_then(Value::as_str) else {
+        return Err((INVALID_REQUEST, "`method` must be a string".to_owned()));
+    };
+
+    let Some(param_names) = method_params(method) else {
+        return Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method)));
+    };
+
+    // Positional params are matched up with the method's fields in order
+    let fields = match envelope.get("params") {
+        None => Map::new(),
+        Some(Value::Object(params)) => params.clone(),
+        Some(Value::Array(params)) if params.len() <= param_names.len() => param_names
+            .iter()
+            .map(|name| name.to_string())
+            .zip(params.iter().cloned())
+            .collect(),
+        Some(Value::Array(_)) => {
+            return Err((INVALID_PARAMS, "too many positional params".to_owned()))
+        }
+        Some(_) => {
+            return Err((
+                INVALID_REQUEST,
+                "`params` must be an array or object".to_owned(),
+            ))
+        }
+    };
+
+    let call = parse_call(method, &fields, config).map_err(|err| {
+        let code = match err {
+            RequestError::UnknownMethod(_) => METHOD_NOT_FOUND,
+            _ => INVALID_PARAMS,
+        };
+        (code, err.to_string())
+    })?;
+
+    // Once the line's budget is spent, the rest of a batch is answered
+    // without being evaluated
+    budget.check().map_err(|_| timed_out())?;
+    let response = handle_request(call, cache, budget).map_err(|_| timed_out())?;
+    response_to_value(response)
+}
+
+fn response_to_value(response: MethodResponse) -> Result<Value, (i64, String)> {
+    match response {
+        // There's no streaming in JSON-RPC, so the chunks become one result.
+        // The range width cap keeps this bounded.
+        MethodResponse::PrimesInRange(chunks) => {
+            let mut primes = Vec::new();
+            for chunk in chunks {
+                primes.extend(chunk.map_err(|_| timed_out())?.primes);
+            }
+            Ok(json!(PrimesInRangeChunk {
+                method: "primesInRange".to_owned(),
+                primes,
+            }))
+        }
+        response => Ok(json!(response)),
+    }
+}
+
+fn timed_out() -> (i64, String) {
+    (
+        REQUEST_TIMEOUT,
+        "request exceeded its time budget".to_owned(),
+    )
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn rpc_line(line: &str, request_budget: Option<Duration>) -> Value {
+        let mut config = ServerConfig::new("127.0.0.1:0".to_owned());
+        config.request_budget = request_budget;
+        let cache = PrimeCache::new(1000, 16);
+        match parse_rpc_line(line.as_bytes(), &config) {
+            Ok(value) => handle_rpc_line(value, &config, &cache).expect("a response"),
+            Err(reply) => reply,
+        }
+    }
+
+    fn batch(method: &str, params: &str, count: usize) -> String {
+        let requests: Vec<_> = (0..count)
+            .map(|id| {
+                format!(
+                    r#"{{"jsonrpc":"2.0","method":"{}","params":{},"id":{}}}"#,
+                    method, params, id
+                )
+            })
+            .collect();
+        format!("[{}]", requests.join(","))
+    }
+
+    #[test]
+    fn batches_are_answered_in_order() {
+        let responses = rpc_line(&batch("isPrime", "[7]", 3), None);
+        let ids: Vec<_> = responses
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|response| response["id"].clone())
+            .collect();
+        assert_eq!(ids, [json!(0), json!(1), json!(2)]);
+        assert!(responses[0]["result"]["prime"].as_bool().unwrap());
+    }
+
+    #[test]
+    fn a_batch_shares_one_budget() {
+        // 2^9941 - 1 is prime, and far too big to test in 100ms
+        let mersenne = (num_bigint::BigInt::from(1) << 9941u32) - 1;
+        let line = batch("isPrime", &format!("[{}]", mersenne), 10);
+
+        let started = Instant::now();
+        let responses = rpc_line(&line, Some(Duration::from_millis(100)));
+        // Ten budgets of their own would take a second at least
+        assert!(started.elapsed() < Duration::from_millis(900));
+
+        for response in responses.as_array().unwrap() {
+            assert_eq!(response["error"]["code"], json!(REQUEST_TIMEOUT));
+        }
+    }
+
+    #[test]
+    fn batches_over_the_limit_are_not_evaluated() {
+        let response = rpc_line(&batch("isPrime", "[7]", 101), None);
+        assert_eq!(response["error"]["code"], json!(INVALID_REQUEST));
+        assert_eq!(response["id"], Value::Null);
+
+        let responses = rpc_line(&batch("isPrime", "[7]", 100), None);
+        assert_eq!(responses.as_array().unwrap().len(), 100);
+    }
+}
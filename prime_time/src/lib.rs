@@ -0,0 +1,37 @@
+//! The Prime Time service: primality answers over newline-delimited JSON,
+//! JSON-RPC, binary framings and HTTP. The binary is a thin wrapper around
+//! `serve`, and the request handling is exposed for embedding.
+
+mod budget;
+mod cache;
+mod certificate;
+mod config;
+mod counting;
+mod factor;
+mod framing;
+mod http;
+mod jsonrpc;
+mod limits;
+mod methods;
+mod number;
+mod pool;
+mod primality;
+mod server;
+mod sieve;
+
+pub use budget::{Budget, Timeout};
+pub use cache::{CacheStats, PrimeCache};
+pub use certificate::{verify_certificate, Certificate};
+pub use config::{Protocol, ServerConfig};
+pub use framing::Framing;
+pub use http::serve_http;
+pub use limits::{Limits, Rejection};
+pub use methods::{
+    handle_request, parse_request, respond_success, FactorizeRequest, FactorizeResponse,
+    IsProbablePrimeRequest, IsProbablePrimeResponse, MethodCall, MethodResponse, NextPrimeRequest,
+    NextPrimeResponse, PrevPrimeRequest, PrevPrimeResponse, PrimeCountRequest, PrimeCountResponse,
+    PrimesInRangeChunk, PrimesInRangeRequest, PrimesInRangeStream, Request, RequestError, Response,
+    StreamDone,
+};
+pub use primality::is_prime;
+pub use server::serve;
@@ -0,0 +1,313 @@
+use crate::config::ServerConfig;
+use crate::methods::{ErrorDetail, ErrorResponse};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Past this many peers, the one heard from longest ago is forgotten
+const MAX_TRACKED_PEERS: usize = 10_000;
+
+// How often buckets that have refilled completely are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+// What we suggest to clients turned away because the server is saturated
+const OVERLOADED_RETRY_AFTER: Duration = Duration::from_millis(100);
+
+/// Admission control shared by every listener: a token bucket per peer IP,
+/// plus a cap on how many computations may be queued or running at once.
+#[derive(Clone)]
+pub struct Limits {
+    inner: Arc<LimitsInner>,
+}
+
+struct LimitsInner {
+    // Tokens added per second, or None when rate limiting is off
+    rate: Option<f64>,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+    max_active: usize,
+    active: AtomicUsize,
+}
+
+// The token bucket for each peer. Recency is tracked with a counter, and the
+// peer heard from longest ago is the smallest key in `by_age`.
+struct Buckets {
+    clock: u64,
+    by_peer: HashMap<IpAddr, Bucket>,
+    by_age: BTreeMap<u64, IpAddr>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    age: u64,
+}
+
+/// Why a request was turned away without being evaluated.
+#[derive(Debug)]
+pub enum Rejection {
+    // The peer has used up its share and should wait this long
+    RateLimited { retry_after: Duration },
+    // Too many computations are already in progress across all clients
+    Overloaded,
+}
+
+/// Held for as long as a computation counts against the global cap.
+pub struct ComputePermit {
+    inner: Arc<LimitsInner>,
+    // How many slots in the global cap this permit holds
+    requests: usize,
+}
+
+impl Limits {
+    pub fn new(config: &ServerConfig) -> Self {
+        let inner = Arc::new(LimitsInner {
+            rate: config.rate_limit.map(f64::from),
+            burst: f64::from(config.rate_burst),
+            buckets: Mutex::new(Buckets {
+                clock: 0,
+                by_peer: HashMap::new(),
+                by_age: BTreeMap::new(),
+            }),
+            max_active: config.max_active,
+            active: AtomicUsize::new(0),
+        });
+
+        if let Some(rate) = inner.rate {
+            let inner = Arc::downgrade(&inner);
+            thread::spawn(move || prune_periodically(inner, rate));
+        }
+
+        Self { inner }
+    }
+
+    /// Lets one request from peer through, or says why it can't go ahead.
+    /// Requests from an unknown peer are only subject to the global cap.
+    pub fn admit(&self, peer: Option<IpAddr>) -> Result<ComputePermit, Rejection> {
+        self.admit_batch(peer, 1)
+    }
+
+    /// Lets a batch of requests from peer through together, charging each
+    /// of them against the peer's bucket and the global cap. A batch bigger
+    /// than the burst or the cap is charged as much as either can hold, so
+    /// that it can still get through once everything else has drained.
+    pub fn admit_batch(
+        &self,
+        peer: Option<IpAddr>,
+        requests: usize,
+    ) -> Result<ComputePermit, Rejection> {
+        if let (Some(rate), Some(peer)) = (self.inner.rate, peer) {
+            let tokens = (requests as f64).min(self.inner.burst);
+            self.take_tokens(peer, rate, tokens)?;
+        }
+
+        let requests = requests.min(self.inner.max_active);
+        let active = self.inner.active.fetch_add(requests, Ordering::AcqRel);
+        if active + requests > self.inner.max_active {
+            self.inner.active.fetch_sub(requests, Ordering::AcqRel);
+            return Err(Rejection::Overloaded);
+        }
+
+        Ok(ComputePermit {
+            inner: Arc::clone(&self.inner),
+            requests,
+        })
+    }
+
+    fn take_tokens(&self, peer: IpAddr, rate: f64, tokens: f64) -> Result<(), Rejection> {
+        let now = Instant::now();
+        let burst = self.inner.burst;
+        let mut buckets = self.inner.buckets.lock();
+        let buckets = &mut *buckets;
+        let peer = bucket_key(peer);
+
+        buckets.clock += 1;
+        let age = buckets.clock;
+        let bucket = match buckets.by_peer.get_mut(&peer) {
+            Some(bucket) => {
+                // Move the bucket to the young end
+                buckets.by_age.remove(&bucket.age);
+                bucket
+            }
+            None => {
+                if buckets.by_peer.len() >= MAX_TRACKED_PEERS {
+                    if let Some((_, oldest)) = buckets.by_age.pop_first() {
+                        buckets.by_peer.remove(&oldest);
+                    }
+                }
+                buckets.by_peer.entry(peer).or_insert(Bucket {
+                    tokens: burst,
+                    refilled_at: now,
+                    age,
+                })
+            }
+        };
+        bucket.age = age;
+        buckets.by_age.insert(age, peer);
+
+        if bucket.refill(now, rate, burst) >= tokens {
+            bucket.tokens -= tokens;
+            Ok(())
+        } else {
+            let wait = (tokens - bucket.tokens) / rate;
+            Err(Rejection::RateLimited {
+                retry_after: Duration::from_secs_f64(wait),
+            })
+        }
+    }
+}
+
+impl LimitsInner {
+    // A bucket that has refilled completely is no different from a fresh one,
+    // so those are the ones to forget
+    fn prune(&self, rate: f64) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        let Buckets {
+            by_peer, by_age, ..
+        } = &mut *buckets;
+
+        by_peer.retain(|_, bucket| bucket.refill(now, rate, self.burst) < self.burst);
+        by_age.retain(|_, peer| by_peer.contains_key(peer));
+    }
+}
+
+// Runs for as long as the limits it prunes are in use
+fn prune_periodically(inner: Weak<LimitsInner>, rate: f64) {
+    loop {
+        thread::sleep(PRUNE_INTERVAL);
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        inner.prune(rate);
+    }
+}
+
+// The addresses in an IPv6 /64 usually belong to one client, who could
+// otherwise take a fresh address, and with it a fresh bucket, at will
+fn bucket_key(peer: IpAddr) -> IpAddr {
+    match peer.to_canonical() {
+        IpAddr::V6(peer) => IpAddr::V6(Ipv6Addr::from(u128::from(peer) & !u128::from(u64::MAX))),
+        peer => peer,
+    }
+}
+
+impl Bucket {
+    // Tops the bucket up for the time since it was last touched
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.refilled_at = now;
+        self.tokens
+    }
+}
+
+impl Drop for ComputePermit {
+    fn drop(&mut self) {
+        self.inner.active.fetch_sub(self.requests, Ordering::AcqRel);
+    }
+}
+
+impl Rejection {
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            Rejection::RateLimited { retry_after } => *retry_after,
+            Rejection::Overloaded => OVERLOADED_RETRY_AFTER,
+        }
+    }
+
+    /// A stable, machine-readable name for the rejection.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Rejection::RateLimited { .. } => "rateLimited",
+            Rejection::Overloaded => "overloaded",
+        }
+    }
+
+    pub fn to_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorDetail {
+                kind: self.kind(),
+                message: self.to_string(),
+                retry_after_ms: Some(retry_after_ms(self.retry_after())),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::RateLimited { .. } => write!(f, "too many requests from this client"),
+            Rejection::Overloaded => write!(f, "server is at its concurrency limit"),
+        }
+    }
+}
+
+/// A wait rounded up to whole milliseconds, so clients never retry early.
+pub fn retry_after_ms(wait: Duration) -> u64 {
+    wait.as_nanos().div_ceil(1_000_000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn limits(rate: u32, burst: u32) -> Limits {
+        let mut config = ServerConfig::new("127.0.0.1:0".to_owned());
+        config.rate_limit = Some(rate);
+        config.rate_burst = burst;
+        Limits::new(&config)
+    }
+
+    fn tracked(limits: &Limits) -> usize {
+        let buckets = limits.inner.buckets.lock();
+        assert_eq!(buckets.by_peer.len(), buckets.by_age.len());
+        buckets.by_peer.len()
+    }
+
+    #[test]
+    fn the_oldest_peer_is_forgotten_past_the_limit() {
+        let limits = limits(1, 1);
+        let peer = |i: u32| Some(IpAddr::V4(Ipv4Addr::from(i)));
+
+        for i in 0..MAX_TRACKED_PEERS as u32 + 100 {
+            assert!(limits.admit(peer(i)).is_ok());
+        }
+        assert_eq!(tracked(&limits), MAX_TRACKED_PEERS);
+
+        // The first peers were evicted and start over with a full bucket,
+        // while the most recent ones are still remembered
+        assert!(limits.admit(peer(0)).is_ok());
+        assert!(limits.admit(peer(MAX_TRACKED_PEERS as u32 + 99)).is_err());
+    }
+
+    #[test]
+    fn an_ipv6_slash_64_shares_one_bucket() {
+        let limits = limits(1, 2);
+        let peer = |addr: &str| Some(addr.parse().unwrap());
+
+        assert!(limits.admit(peer("2001:db8::1")).is_ok());
+        assert!(limits.admit(peer("2001:db8::ffff:2")).is_ok());
+        assert!(limits.admit(peer("2001:db8::3")).is_err());
+        assert!(limits.admit(peer("2001:db8:0:1::1")).is_ok());
+        assert_eq!(tracked(&limits), 2);
+    }
+
+    #[test]
+    fn pruning_forgets_refilled_buckets() {
+        let limits = limits(1000, 1);
+        assert!(limits.admit(Some(IpAddr::V4(Ipv4Addr::LOCALHOST))).is_ok());
+        assert_eq!(tracked(&limits), 1);
+
+        thread::sleep(Duration::from_millis(10));
+        limits.inner.prune(1000.0);
+        assert_eq!(tracked(&limits), 0);
+    }
+}
@@ -0,0 +1,829 @@
+use crate::budget::{Budget, Timeout};
+use crate::cache::PrimeCache;
+use crate::certificate::{certify, verify_certificate, Certificate};
+use crate::config::ServerConfig;
+use crate::counting::{prime_count, MAX_PRIME_COUNT_INPUT};
+use crate::factor::factorize;
+use crate::framing::{write_message, Framing};
+use crate::number::{exact_value, integer_to_number, ExactValue, MAX_EXPANDED_DIGITS};
+use crate::primality::{is_probable_prime, next_prime, prev_prime};
+use crate::sieve::{SegmentedSieve, MAX_SIEVE_END};
+use num_bigint::BigInt;
+use num_traits::{Signed, ToPrimitive};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+// Upper bound on Miller-Rabin rounds a client may ask for
+const MAX_ROUNDS: u32 = 256;
+const DEFAULT_ROUNDS: u32 = 25;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub method: String,
+    // Kept as the original JSON number text so that integers of any size,
+    // negatives and fractional values can be told apart exactly
+    pub number: Number,
+    // Asks for a certificate backing the answer
+    #[serde(default)]
+    pub certificate: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub method: String,
+    pub prime: bool,
+    // Only present when requested and we were able to produce one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<Certificate>,
+}
+
+#[derive(Debug)]
+pub struct NextPrimeRequest {
+    pub number: BigInt,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NextPrimeResponse {
+    pub method: String,
+    pub number: Number,
+}
+
+#[derive(Debug)]
+pub struct PrevPrimeRequest {
+    pub number: BigInt,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PrevPrimeResponse {
+    pub method: String,
+    // None when there is no smaller prime
+    pub number: Option<Number>,
+}
+
+#[derive(Debug)]
+pub struct FactorizeRequest {
+    pub number: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FactorizeResponse {
+    pub method: String,
+    // Prime factors in ascending order, repeated by multiplicity
+    pub factors: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub struct PrimeCountRequest {
+    pub number: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PrimeCountResponse {
+    pub method: String,
+    pub count: u64,
+}
+
+#[derive(Debug)]
+pub struct IsProbablePrimeRequest {
+    pub number: Number,
+    pub rounds: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IsProbablePrimeResponse {
+    pub method: String,
+    pub prime: bool,
+}
+
+#[derive(Debug)]
+pub struct PrimesInRangeRequest {
+    // Both ends are inclusive
+    pub from: u64,
+    pub to: u64,
+}
+
+/// One NDJSON line of a primesInRange stream.
+#[derive(Serialize, Debug)]
+pub struct PrimesInRangeChunk {
+    pub method: String,
+    pub primes: Vec<u64>,
+}
+
+/// The line that terminates every streamed response.
+#[derive(Serialize, Debug)]
+pub struct StreamDone {
+    pub done: bool,
+}
+
+/// Lazily sieves a primesInRange request one chunk at a time, so the full
+/// result never has to sit in memory. The request's budget still applies
+/// while the chunks are written out, and a stream that runs past it ends with
+/// a single `Err(Timeout)`.
+#[derive(Debug)]
+pub struct PrimesInRangeStream {
+    sieve: SegmentedSieve,
+    budget: Budget,
+    timed_out: bool,
+}
+
+impl Iterator for PrimesInRangeStream {
+    type Item = Result<PrimesInRangeChunk, Timeout>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.timed_out {
+            return None;
+        }
+        if let Err(timeout) = self.budget.check() {
+            self.timed_out = true;
+            return Some(Err(timeout));
+        }
+
+        self.sieve.next().map(|primes| {
+            Ok(PrimesInRangeChunk {
+                method: "primesInRange".to_owned(),
+                primes,
+            })
+        })
+    }
+}
+
+/// A validated request for any of the methods in the registry.
+#[derive(Debug)]
+pub enum MethodCall {
+    IsPrime(Request),
+    NextPrime(NextPrimeRequest),
+    PrevPrime(PrevPrimeRequest),
+    Factorize(FactorizeRequest),
+    PrimeCount(PrimeCountRequest),
+    IsProbablePrime(IsProbablePrimeRequest),
+    PrimesInRange(PrimesInRangeRequest),
+}
+
+impl MethodCall {
+    /// The registry name of the method being called.
+    pub fn method_name(&self) -> &'static str {
+        match self {
+            MethodCall::IsPrime(_) => "isPrime",
+            MethodCall::NextPrime(_) => "nextPrime",
+            MethodCall::PrevPrime(_) => "prevPrime",
+            MethodCall::Factorize(_) => "factorize",
+            MethodCall::PrimeCount(_) => "primeCount",
+            MethodCall::IsProbablePrime(_) => "isProbablePrime",
+            MethodCall::PrimesInRange(_) => "primesInRange",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum MethodResponse {
+    IsPrime(Response),
+    NextPrime(NextPrimeResponse),
+    PrevPrime(PrevPrimeResponse),
+    Factorize(FactorizeResponse),
+    PrimeCount(PrimeCountResponse),
+    IsProbablePrime(IsProbablePrimeResponse),
+    // Streamed line by line rather than serialized as a single value
+    #[serde(skip_serializing)]
+    PrimesInRange(PrimesInRangeStream),
+}
+
+// Every way a request line can be malformed. Any of these gets a malformed
+// response and closes the connection.
+#[derive(Debug)]
+pub enum RequestError {
+    InvalidJson(serde_json::Error),
+    NotAnObject,
+    MissingField(&'static str),
+    WrongType {
+        field: &'static str,
+        expected: &'static str,
+    },
+    OutOfRange {
+        field: &'static str,
+        expected: String,
+    },
+    UnknownMethod(String),
+    InvalidUtf8,
+    LineTooLong(usize),
+    // A MessagePack or CBOR payload that doesn't decode
+    InvalidFrame(String),
+    FrameTooLong(usize),
+}
+
+/// The body of a diagnostic error response.
+#[derive(Serialize, Debug)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorDetail {
+    pub kind: &'static str,
+    pub message: String,
+    // How long a client that was turned away should back off for
+    #[serde(rename = "retryAfterMs", skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+/// What a request that ran out of budget gets instead of its answer.
+pub fn timeout_response() -> ErrorResponse {
+    ErrorResponse {
+        error: ErrorDetail {
+            kind: "timeout",
+            message: "request exceeded its time budget".to_owned(),
+            retry_after_ms: None,
+        },
+    }
+}
+
+impl RequestError {
+    /// A stable, machine-readable name for the kind of problem.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RequestError::InvalidJson(_) => "invalidJson",
+            RequestError::NotAnObject => "notAnObject",
+            RequestError::MissingField(_) => "missingField",
+            RequestError::WrongType { .. } => "wrongType",
+            RequestError::OutOfRange { .. } => "outOfRange",
+            RequestError::UnknownMethod(_) => "unknownMethod",
+            RequestError::InvalidUtf8 => "invalidUtf8",
+            RequestError::LineTooLong(_) => "lineTooLong",
+            RequestError::InvalidFrame(_) => "invalidFrame",
+            RequestError::FrameTooLong(_) => "frameTooLong",
+        }
+    }
+
+    pub fn to_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorDetail {
+                kind: self.kind(),
+                message: self.to_string(),
+                retry_after_ms: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::InvalidJson(err) => write!(f, "invalid JSON: {}", err),
+            RequestError::NotAnObject => write!(f, "request must be a JSON object"),
+            RequestError::MissingField(field) => write!(f, "missing field `{}`", field),
+            RequestError::WrongType { field, expected } => {
+                write!(f, "field `{}` must be {}", field, expected)
+            }
+            RequestError::OutOfRange { field, expected } => {
+                write!(f, "field `{}` must be {}", field, expected)
+            }
+            RequestError::UnknownMethod(method) => write!(f, "unknown method `{}`", method),
+            RequestError::InvalidUtf8 => write!(f, "request is not valid UTF-8"),
+            RequestError::LineTooLong(limit) => {
+                write!(f, "request line exceeds {} bytes", limit)
+            }
+            RequestError::InvalidFrame(err) => write!(f, "invalid frame: {}", err),
+            RequestError::FrameTooLong(limit) => {
+                write!(f, "request frame exceeds {} bytes", limit)
+            }
+        }
+    }
+}
+
+// Method Registry
+
+struct Method {
+    name: &'static str,
+    // Field names in order, used to match up positional JSON-RPC params
+    params: &'static [&'static str],
+    // Validates the request's fields and builds the typed request
+    parse: fn(&Map<String, Value>, &ServerConfig) -> Result<MethodCall, RequestError>,
+}
+
+const METHODS: [Method; 7] = [
+    Method {
+        name: "isPrime",
+        params: &["number"],
+        parse: parse_is_prime,
+    },
+    Method {
+        name: "nextPrime",
+        params: &["number"],
+        parse: parse_next_prime,
+    },
+    Method {
+        name: "prevPrime",
+        params: &["number"],
+        parse: parse_prev_prime,
+    },
+    Method {
+        name: "factorize",
+        params: &["number"],
+        parse: parse_factorize,
+    },
+    Method {
+        name: "primeCount",
+        params: &["number"],
+        parse: parse_prime_count,
+    },
+    Method {
+        name: "isProbablePrime",
+        params: &["number", "rounds"],
+        parse: parse_is_probable_prime,
+    },
+    Method {
+        name: "primesInRange",
+        params: &["from", "to"],
+        parse: parse_primes_in_range,
+    },
+];
+
+// Request Parsing
+
+pub fn parse_request(
+    request_data: &str,
+    config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    let value: Value = serde_json::from_str(request_data).map_err(RequestError::InvalidJson)?;
+    parse_request_value(value, config)
+}
+
+/// Validates a request that has already been decoded, whatever its framing.
+pub fn parse_request_value(
+    value: Value,
+    config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    let Value::Object(fields) = value else {
+        return Err(RequestError::NotAnObject);
+    };
+
+    // Unknown fields are ignored, but the ones we know about must be exact
+    let method = string_field(&fields, "method")?;
+    parse_call(method, &fields, config)
+}
+
+/// Validates the fields for a named method and builds its typed request.
+pub fn parse_call(
+    method: &str,
+    fields: &Map<String, Value>,
+    config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    let entry = METHODS
+        .iter()
+        .find(|entry| entry.name == method)
+        .ok_or_else(|| RequestError::UnknownMethod(method.to_owned()))?;
+
+    (entry.parse)(fields, config)
+}
+
+/// The ordered field names for a method, or None if there's no such method.
+pub fn method_params(method: &str) -> Option<&'static [&'static str]> {
+    METHODS
+        .iter()
+        .find(|entry| entry.name == method)
+        .map(|entry| entry.params)
+}
+
+fn parse_is_prime(
+    fields: &Map<String, Value>,
+    _config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    Ok(MethodCall::IsPrime(Request {
+        method: "isPrime".to_owned(),
+        number: testable_number_field(fields, "number")?.clone(),
+        certificate: optional_bool_field(fields, "certificate")?.unwrap_or(false),
+    }))
+}
+
+fn parse_next_prime(
+    fields: &Map<String, Value>,
+    _config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    Ok(MethodCall::NextPrime(NextPrimeRequest {
+        number: integer_field(fields, "number")?,
+    }))
+}
+
+fn parse_prev_prime(
+    fields: &Map<String, Value>,
+    _config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    Ok(MethodCall::PrevPrime(PrevPrimeRequest {
+        number: integer_field(fields, "number")?,
+    }))
+}
+
+fn parse_factorize(
+    fields: &Map<String, Value>,
+    _config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    let number = integer_field(fields, "number")?;
+    match number.to_u64() {
+        Some(number) if number > 0 => Ok(MethodCall::Factorize(FactorizeRequest { number })),
+        _ => Err(RequestError::OutOfRange {
+            field: "number",
+            expected: format!("between 1 and {}", u64::MAX),
+        }),
+    }
+}
+
+fn parse_prime_count(
+    fields: &Map<String, Value>,
+    _config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    let number = integer_field(fields, "number")?;
+
+    // Nothing below 2 is prime, so every negative input counts zero primes
+    if number.is_negative() {
+        return Ok(MethodCall::PrimeCount(PrimeCountRequest { number: 0 }));
+    }
+
+    match number.to_u64() {
+        Some(number) if number <= MAX_PRIME_COUNT_INPUT => {
+            Ok(MethodCall::PrimeCount(PrimeCountRequest { number }))
+        }
+        _ => Err(RequestError::OutOfRange {
+            field: "number",
+            expected: format!("at most {}", MAX_PRIME_COUNT_INPUT),
+        }),
+    }
+}
+
+fn parse_is_probable_prime(
+    fields: &Map<String, Value>,
+    _config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    let rounds = match fields.get("rounds") {
+        None => DEFAULT_ROUNDS,
+        Some(_) => match integer_field(fields, "rounds")?.to_u32() {
+            Some(rounds) if (1..=MAX_ROUNDS).contains(&rounds) => rounds,
+            _ => {
+                return Err(RequestError::OutOfRange {
+                    field: "rounds",
+                    expected: format!("between 1 and {}", MAX_ROUNDS),
+                })
+            }
+        },
+    };
+
+    Ok(MethodCall::IsProbablePrime(IsProbablePrimeRequest {
+        number: testable_number_field(fields, "number")?.clone(),
+        rounds,
+    }))
+}
+
+fn parse_primes_in_range(
+    fields: &Map<String, Value>,
+    config: &ServerConfig,
+) -> Result<MethodCall, RequestError> {
+    let from = integer_field(fields, "from")?;
+    let to = integer_field(fields, "to")?;
+
+    // Nothing below 2 is prime, so negative bounds can be clamped to zero
+    let from = from
+        .to_u64()
+        .unwrap_or(if from.is_negative() { 0 } else { u64::MAX });
+    let to = match to.to_u64() {
+        Some(to) if to <= MAX_SIEVE_END => to,
+        _ if to.is_negative() => 0,
+        _ => {
+            return Err(RequestError::OutOfRange {
+                field: "to",
+                expected: format!("at most {}", MAX_SIEVE_END),
+            })
+        }
+    };
+
+    if to >= from && to - from >= config.max_range_width {
+        return Err(RequestError::OutOfRange {
+            field: "to",
+            expected: format!("within {} of `from`", config.max_range_width),
+        });
+    }
+
+    Ok(MethodCall::PrimesInRange(PrimesInRangeRequest { from, to }))
+}
+
+// Field Validation
+
+fn required_field<'a>(
+    fields: &'a Map<String, Value>,
+    field: &'static str,
+) -> Result<&'a Value, RequestError> {
+    fields.get(field).ok_or(RequestError::MissingField(field))
+}
+
+fn string_field<'a>(
+    fields: &'a Map<String, Value>,
+    field: &'static str,
+) -> Result<&'a str, RequestError> {
+    required_field(fields, field)?
+        .as_str()
+        .ok_or(RequestError::WrongType {
+            field,
+            expected: "a string",
+        })
+}
+
+fn number_field<'a>(
+    fields: &'a Map<String, Value>,
+    field: &'static str,
+) -> Result<&'a Number, RequestError> {
+    // Numeric strings like "7" are rejected, only real JSON numbers count
+    match required_field(fields, field)? {
+        Value::Number(number) => Ok(number),
+        _ => Err(RequestError::WrongType {
+            field,
+            expected: "a number",
+        }),
+    }
+}
+
+// Any number can be asked about, except a positive integer too long to test.
+// Calling that composite would only be a guess.
+fn testable_number_field<'a>(
+    fields: &'a Map<String, Value>,
+    field: &'static str,
+) -> Result<&'a Number, RequestError> {
+    let number = number_field(fields, field)?;
+    match exact_value(number) {
+        ExactValue::TooManyDigits { negative: false } => Err(RequestError::OutOfRange {
+            field,
+            expected: format!("at most {} digits", MAX_EXPANDED_DIGITS),
+        }),
+        _ => Ok(number),
+    }
+}
+
+fn optional_bool_field(
+    fields: &Map<String, Value>,
+    field: &'static str,
+) -> Result<Option<bool>, RequestError> {
+    match fields.get(field) {
+        None => Ok(None),
+        Some(Value::Bool(value)) => Ok(Some(*value)),
+        Some(_) => Err(RequestError::WrongType {
+            field,
+            expected: "a boolean",
+        }),
+    }
+}
+
+fn integer_field(fields: &Map<String, Value>, field: &'static str) -> Result<BigInt, RequestError> {
+    match exact_value(number_field(fields, field)?) {
+        ExactValue::Integer(number) => Ok(number),
+        ExactValue::HugeInteger { .. } | ExactValue::TooManyDigits { .. } => {
+            Err(RequestError::OutOfRange {
+                field,
+                expected: "an integer of practical size".to_owned(),
+            })
+        }
+        ExactValue::Fraction => Err(RequestError::WrongType {
+            field,
+            expected: "an integer",
+        }),
+    }
+}
+
+// Request Handling
+
+/// Evaluates a request, giving up with `Timeout` once the budget is spent.
+pub fn handle_request(
+    call: MethodCall,
+    cache: &PrimeCache,
+    budget: Budget,
+) -> Result<MethodResponse, Timeout> {
+    let response = match call {
+        MethodCall::IsPrime(request) => {
+            let (prime, certificate) = match exact_value(&request.number) {
+                ExactValue::Integer(number) => {
+                    let prime = cache.is_prime(&number, &budget)?;
+                    let certificate = if request.certificate {
+                        certify(&number, prime, &budget)?
+                    } else {
+                        None
+                    };
+
+                    // Double check our own work so we never hand out a bad proof
+                    let certificate = match certificate {
+                        Some(certificate)
+                            if verify_certificate(&number, &certificate, &budget)? =>
+                        {
+                            Some(certificate)
+                        }
+                        _ => None,
+                    };
+
+                    (prime, certificate)
+                }
+                // Huge integers are multiples of ten, and fractions are never prime
+                ExactValue::HugeInteger { .. }
+                | ExactValue::TooManyDigits { negative: true }
+                | ExactValue::Fraction => (false, None),
+                // parse_request turns these away. A request built some other
+                // way can't be tested in any reasonable time either.
+                ExactValue::TooManyDigits { negative: false } => return Err(Timeout),
+            };
+
+            MethodResponse::IsPrime(Response {
+                method: request.method,
+                prime,
+                certificate,
+            })
+        }
+        MethodCall::NextPrime(request) => MethodResponse::NextPrime(NextPrimeResponse {
+            method: "nextPrime".to_owned(),
+            number: integer_to_number(&next_prime(&request.number, &budget)?),
+        }),
+        MethodCall::PrevPrime(request) => MethodResponse::PrevPrime(PrevPrimeResponse {
+            method: "prevPrime".to_owned(),
+            number: prev_prime(&request.number, &budget)?.map(|prime| integer_to_number(&prime)),
+        }),
+        MethodCall::Factorize(request) => MethodResponse::Factorize(FactorizeResponse {
+            method: "factorize".to_owned(),
+            factors: factorize(request.number),
+        }),
+        MethodCall::PrimeCount(request) => MethodResponse::PrimeCount(PrimeCountResponse {
+            method: "primeCount".to_owned(),
+            count: prime_count(request.number, &budget)?,
+        }),
+        MethodCall::IsProbablePrime(request) => {
+            let prime = match exact_value(&request.number) {
+                ExactValue::Integer(number) => is_probable_prime(&number, request.rounds, &budget)?,
+                ExactValue::HugeInteger { .. }
+                | ExactValue::TooManyDigits { negative: true }
+                | ExactValue::Fraction => false,
+                ExactValue::TooManyDigits { negative: false } => return Err(Timeout),
+            };
+
+            MethodResponse::IsProbablePrime(IsProbablePrimeResponse {
+                method: "isProbablePrime".to_owned(),
+                prime,
+            })
+        }
+        MethodCall::PrimesInRange(request) => MethodResponse::PrimesInRange(PrimesInRangeStream {
+            sieve: SegmentedSieve::new(request.from, request.to),
+            budget,
+            timed_out: false,
+        }),
+    };
+
+    Ok(response)
+}
+
+// Response Writing
+
+pub fn respond_success<W: Write>(
+    mut writer: W,
+    framing: Framing,
+    response: MethodResponse,
+) -> io::Result<()> {
+    match response {
+        // Streams go out one message per chunk, followed by a terminating one.
+        // A stream cut short by its budget ends with the timeout error instead.
+        MethodResponse::PrimesInRange(chunks) => {
+            for chunk in chunks {
+                match chunk {
+                    Ok(chunk) => write_message(&mut writer, framing, &chunk)?,
+                    Err(Timeout) => {
+                        return write_message(&mut writer, framing, &timeout_response())
+                    }
+                }
+            }
+            write_message(&mut writer, framing, &StreamDone { done: true })
+        }
+        response => write_message(&mut writer, framing, &response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_prime_response(line: &str, budget: Budget) -> Response {
+        let config = ServerConfig::new("127.0.0.1:0".to_owned());
+        // A small sieve so most of these reach the real primality test
+        let cache = PrimeCache::new(1000, 16);
+
+        let call = parse_request(line, &config).expect("request is well formed");
+        match handle_request(call, &cache, budget) {
+            Ok(MethodResponse::IsPrime(response)) => response,
+            other => panic!("unexpected response to {}: {:?}", line, other),
+        }
+    }
+
+    fn is_prime_answer(number: &str) -> bool {
+        let line = format!(r#"{{"method":"isPrime","number":{}}}"#, number);
+        is_prime_response(&line, Budget::new(None)).prime
+    }
+
+    fn certified(number: &str, budget: Budget) -> Response {
+        let line = format!(
+            r#"{{"method":"isPrime","number":{},"certificate":true}}"#,
+            number
+        );
+        is_prime_response(&line, budget)
+    }
+
+    #[test]
+    fn is_prime_judges_the_exact_number() {
+        let cases = [
+            // Fractions are never prime, however they'd round
+            ("3.5", false),
+            ("7.5e0", false),
+            ("0.7e1", true),
+            // Whole numbers are judged on their value, whatever their spelling
+            ("7", true),
+            ("7.0", true),
+            ("7e0", true),
+            ("70e-1", true),
+            // Negatives and both zeros
+            ("-7", false),
+            ("-7.0", false),
+            ("0", false),
+            ("-0", false),
+            ("-0.0", false),
+            // Far past f64's integer precision, but still exact
+            ("1e308", false),
+            ("9007199254740992", false),
+            ("9007199254740993", false),
+            ("9007199254740881", true),
+            // Either side of u64::MAX
+            ("18446744073709551557", true),
+            ("18446744073709551615", false),
+            ("18446744073709551616", false),
+            ("18446744073709551617", false),
+            ("18446744073709551629", true),
+            ("-18446744073709551629", false),
+        ];
+
+        for (number, expected) in cases {
+            assert_eq!(is_prime_answer(number), expected, "isPrime({})", number);
+        }
+    }
+
+    #[test]
+    fn is_prime_never_guesses_about_integers_too_long_to_test() {
+        let config = ServerConfig::new("127.0.0.1:0".to_owned());
+        let written_out = "7".repeat(MAX_EXPANDED_DIGITS + 1);
+
+        let line = format!(r#"{{"method":"isPrime","number":{}}}"#, written_out);
+        assert!(matches!(
+            parse_request(&line, &config),
+            Err(RequestError::OutOfRange {
+                field: "number",
+                ..
+            })
+        ));
+
+        // The sign or a trailing zero still settles these without a test
+        assert!(!is_prime_answer(&format!("-{}", written_out)));
+        assert!(!is_prime_answer(&format!("{}0", written_out)));
+        assert!(!is_prime_answer("1e5000"));
+    }
+
+    #[test]
+    fn certificates_back_the_answer() {
+        let budget = Budget::new(None);
+
+        let response = certified("1000003", budget);
+        assert!(response.prime);
+        assert!(matches!(
+            response.certificate,
+            Some(Certificate::Pratt { n: 1000003, .. })
+        ));
+
+        let response = certified("1000001", budget);
+        assert!(!response.prime);
+        assert!(matches!(
+            response.certificate,
+            Some(Certificate::Factor { .. })
+        ));
+
+        // 2^64 + 1 has no small factors, so this takes a Fermat witness
+        let response = certified("18446744073709551617", budget);
+        assert!(!response.prime);
+        let certificate = response
+            .certificate
+            .expect("a composite gets a certificate");
+        assert!(matches!(certificate, Certificate::FermatWitness { .. }));
+        let number = BigInt::from(u64::MAX) + 2;
+        assert_eq!(verify_certificate(&number, &certificate, &budget), Ok(true));
+    }
+
+    #[test]
+    fn big_primes_with_certificate_requested_are_answered_within_budget() {
+        // 2^607 - 1. There's no certificate for primes this big, and looking
+        // for a witness of compositeness used to spend the whole budget.
+        let mersenne = "531137992816767098689588206552468627329593117727031923199444138200403559860852242739162502265229285668889329486246501015346579337652707239409519978766587351943831270835393219031728127";
+        let budget = Budget::new(Some(std::time::Duration::from_secs(5)));
+
+        let response = certified(mersenne, budget);
+        assert!(response.prime);
+        assert_eq!(response.certificate, None);
+    }
+}
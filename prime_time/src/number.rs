@@ -0,0 +1,169 @@
+use num_bigint::BigInt;
+use serde_json::Number;
+use std::fmt::Display;
+
+// Largest integer, in decimal digits, we'll materialize. Past this the value is
+// only classified, never parsed.
+pub const MAX_EXPANDED_DIGITS: usize = 4096;
+
+/// The exact value of a JSON number, read from its original decimal text
+/// rather than through a lossy f64.
+#[derive(Debug, PartialEq)]
+pub enum ExactValue {
+    /// A whole number, positive or negative
+    Integer(BigInt),
+
+    /// A whole number with more digits than we're willing to expand, where
+    /// the exponent adds at least one zero. These are always multiples of ten.
+    HugeInteger { negative: bool },
+
+    /// A whole number whose own digits, written out in the text, are more
+    /// than we're willing to expand. Nothing is known about its factors.
+    TooManyDigits { negative: bool },
+
+    /// Any number with a nonzero fractional part
+    Fraction,
+}
+
+pub fn exact_value(number: &Number) -> ExactValue {
+    // Fast path for the common case of small integers
+    if let Some(num) = number.as_u64() {
+        return ExactValue::Integer(BigInt::from(num));
+    }
+    if let Some(num) = number.as_i64() {
+        return ExactValue::Integer(BigInt::from(num));
+    }
+
+    parse_decimal(number.as_str()).unwrap_or(ExactValue::Fraction)
+}
+
+// Parses JSON number text (`-?int(.frac)?([eE][+-]?exp)?`) without rounding.
+fn parse_decimal(text: &str) -> Option<ExactValue> {
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+
+    let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+        Some(index) => (&unsigned[..index], &unsigned[index + 1..]),
+        None => (unsigned, "0"),
+    };
+
+    let (int_part, frac_part) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    // The value is digits * 10^shift, where digits has no leading zeros
+    let digits = format!("{}{}", int_part, frac_part);
+    let digits = digits.trim_start_matches('0');
+    let trimmed = digits.trim_end_matches('0');
+    if trimmed.is_empty() {
+        return Some(ExactValue::Integer(BigInt::from(0)));
+    }
+
+    // Exponents too large for an i64 are either enormous or vanishingly small
+    let exponent = match exponent.parse::<i64>() {
+        Ok(exponent) => exponent,
+        Err(_) if exponent.starts_with('-') => return Some(ExactValue::Fraction),
+        Err(_) => return Some(ExactValue::HugeInteger { negative }),
+    };
+
+    let trailing_zeros = (digits.len() - trimmed.len()) as i64;
+    let shift = exponent
+        .saturating_sub(frac_part.len() as i64)
+        .saturating_add(trailing_zeros);
+
+    if shift < 0 {
+        return Some(ExactValue::Fraction);
+    }
+
+    // A positive shift appends zeros, so only then is the value known to be
+    // a multiple of ten
+    if trimmed.len() as i64 + shift > MAX_EXPANDED_DIGITS as i64 {
+        return Some(if shift > 0 {
+            ExactValue::HugeInteger { negative }
+        } else {
+            ExactValue::TooManyDigits { negative }
+        });
+    }
+
+    let expanded = format!("{}{}", trimmed, "0".repeat(shift as usize));
+    let magnitude = expanded.parse::<BigInt>().ok()?;
+
+    Some(ExactValue::Integer(if negative {
+        -magnitude
+    } else {
+        magnitude
+    }))
+}
+
+/// A JSON number holding an integer of any width, signed or not.
+pub fn integer_to_number<T: Display>(integer: &T) -> Number {
+    // With arbitrary precision enabled any integer text is a valid Number
+    serde_json::from_str(&integer.to_string()).expect("integers are valid JSON numbers")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value_of(text: &str) -> ExactValue {
+        exact_value(&serde_json::from_str(text).expect("valid JSON number"))
+    }
+
+    fn integer(text: &str) -> ExactValue {
+        ExactValue::Integer(text.parse().expect("valid integer"))
+    }
+
+    #[test]
+    fn integers_keep_every_digit() {
+        assert_eq!(
+            value_of("340282366920938463463374607431768211507"),
+            integer("340282366920938463463374607431768211507")
+        );
+        assert_eq!(
+            value_of("-340282366920938463463374607431768211507.000"),
+            integer("-340282366920938463463374607431768211507")
+        );
+        assert_eq!(value_of("-12.5e1"), integer("-125"));
+        assert_eq!(value_of("0.000e99999999999999999999"), integer("0"));
+    }
+
+    #[test]
+    fn fractions_are_found_however_they_are_written() {
+        assert_eq!(value_of("1.5"), ExactValue::Fraction);
+        assert_eq!(value_of("125e-1"), ExactValue::Fraction);
+        assert_eq!(value_of("5e-324"), ExactValue::Fraction);
+        assert_eq!(value_of("1e-99999999999999999999"), ExactValue::Fraction);
+    }
+
+    #[test]
+    fn oversized_integers_are_classified_by_where_their_digits_come_from() {
+        let at_limit = "7".repeat(MAX_EXPANDED_DIGITS);
+        assert!(matches!(value_of(&at_limit), ExactValue::Integer(_)));
+
+        // Every digit is written out, so nothing is known about the value
+        let written_out = "7".repeat(MAX_EXPANDED_DIGITS + 1);
+        assert_eq!(
+            value_of(&written_out),
+            ExactValue::TooManyDigits { negative: false }
+        );
+        assert_eq!(
+            value_of(&format!("-{}", written_out)),
+            ExactValue::TooManyDigits { negative: true }
+        );
+
+        // A trailing zero, written out or from the exponent, makes it a
+        // multiple of ten
+        assert_eq!(
+            value_of(&format!("{}0", at_limit)),
+            ExactValue::HugeInteger { negative: false }
+        );
+        assert_eq!(
+            value_of("1e5000"),
+            ExactValue::HugeInteger { negative: false }
+        );
+        assert_eq!(
+            value_of("-3e99999999999999999999"),
+            ExactValue::HugeInteger { negative: true }
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use parking_lot::Mutex;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of threads shared by every connection for evaluating requests.
+#[derive(Clone)]
+pub struct WorkerPool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+
+        for _ in 0..workers {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || loop {
+                // Hold the lock only long enough to take the next job
+                let job = queue.lock().recv();
+                match job {
+                    Ok(job) => job(),
+                    // Every sender is gone, so the pool is shutting down
+                    Err(_) => break,
+                }
+            });
+        }
+
+        Self { jobs }
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        // The workers only exit once every sender is dropped, so this can't fail
+        let _ = self.jobs.send(Box::new(job));
+    }
+}
@@ -0,0 +1,500 @@
+use crate::budget::{Budget, Timeout};
+use num_bigint::{BigInt, BigUint, RandBigInt, Sign};
+use num_integer::Integer;
+use num_traits::{One, ToPrimitive, Zero};
+
+// Primes used to cheaply weed out most composites before the heavier tests
+pub const SMALL_PRIMES: [u64; 25] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+];
+
+pub fn is_prime_integer(num: &BigInt, budget: &Budget) -> Result<bool, Timeout> {
+    // Negative numbers are never prime
+    if num.sign() == Sign::Minus {
+        return Ok(false);
+    }
+
+    match num.to_u64() {
+        Some(num) => Ok(is_prime(num)),
+        None => is_prime_big(num.magnitude(), budget),
+    }
+}
+
+// Testing these bases is enough to make Miller-Rabin exact for every u64
+const U64_WITNESSES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+/// Deterministic Miller-Rabin over the whole u64 range.
+pub fn is_prime(num: u64) -> bool {
+    // Numbers less than or equal to 1 are not prime
+    if num <= 1 {
+        return false;
+    }
+
+    // Small primes answer small inputs directly and weed out most composites
+    for prime in SMALL_PRIMES {
+        if num == prime {
+            return true;
+        }
+        if num.is_multiple_of(prime) {
+            return false;
+        }
+    }
+
+    // Anything left below 101^2 has no factor under its square root
+    if num < 101 * 101 {
+        return true;
+    }
+
+    // num - 1 = odd_part * 2^shift
+    let shift = (num - 1).trailing_zeros();
+    let odd_part = (num - 1) >> shift;
+
+    'witness: for witness in U64_WITNESSES {
+        let mut x = pow_mod(witness, odd_part, num);
+        if x == 1 || x == num - 1 {
+            continue;
+        }
+
+        for _ in 1..shift {
+            x = mul_mod(x, x, num);
+            if x == num - 1 {
+                continue 'witness;
+            }
+        }
+
+        return false;
+    }
+
+    true
+}
+
+pub fn mul_mod(a: u64, b: u64, modulus: u64) -> u64 {
+    ((a as u128 * b as u128) % modulus as u128) as u64
+}
+
+pub fn pow_mod(mut base: u64, mut exponent: u64, modulus: u64) -> u64 {
+    let mut result = 1;
+    base %= modulus;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_mod(result, base, modulus);
+        }
+        base = mul_mod(base, base, modulus);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Baillie-PSW: a strong base-2 Miller-Rabin test followed by a strong Lucas
+/// test. No composite is known to pass both.
+pub fn is_prime_big(num: &BigUint, budget: &Budget) -> Result<bool, Timeout> {
+    if let Some(small) = num.to_u64() {
+        return Ok(is_prime(small));
+    }
+
+    for prime in SMALL_PRIMES {
+        if (num % prime).is_zero() {
+            return Ok(false);
+        }
+    }
+
+    Ok(is_strong_probable_prime(num, &BigUint::from(2u32), budget)?
+        && is_strong_lucas_probable_prime(num, budget)?)
+}
+
+// Miller-Rabin test of an odd num > 2 against a single base
+fn is_strong_probable_prime(
+    num: &BigUint,
+    base: &BigUint,
+    budget: &Budget,
+) -> Result<bool, Timeout> {
+    let one = BigUint::one();
+    let num_minus_one = num - &one;
+    let shift = num_minus_one.trailing_zeros().unwrap_or(0);
+    let odd_part = &num_minus_one >> shift;
+
+    let mut x = pow_mod_big(base, &odd_part, num, budget)?;
+    if x == one || x == num_minus_one {
+        return Ok(true);
+    }
+
+    for _ in 1..shift {
+        budget.check()?;
+        x = (&x * &x) % num;
+        if x == num_minus_one {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// base^exponent mod modulus, checking the budget between steps. BigUint's own
+/// modpow can't be interrupted, and on huge numbers it runs for minutes.
+pub fn pow_mod_big(
+    base: &BigUint,
+    exponent: &BigUint,
+    modulus: &BigUint,
+    budget: &Budget,
+) -> Result<BigUint, Timeout> {
+    let base = base % modulus;
+    let mut result = BigUint::one() % modulus;
+
+    // Left-to-right square and multiply
+    for bit in (0..exponent.bits()).rev() {
+        budget.check()?;
+        result = (&result * &result) % modulus;
+        if exponent.bit(bit) {
+            result = (&result * &base) % modulus;
+        }
+    }
+
+    Ok(result)
+}
+
+// Strong Lucas test with Selfridge's parameter choice, for an odd num with no
+// small factors
+fn is_strong_lucas_probable_prime(num: &BigUint, budget: &Budget) -> Result<bool, Timeout> {
+    // Perfect squares have no D with jacobi(D, num) == -1, so the search below
+    // would never end
+    let root = num.sqrt();
+    if &root * &root == *num {
+        return Ok(false);
+    }
+
+    // Find the first D in 5, -7, 9, -11, ... with jacobi(D, num) == -1
+    let mut d: i64 = 5;
+    loop {
+        match jacobi(d, num) {
+            -1 => break,
+            // num is far larger than D, so a shared factor means it's composite
+            0 => return Ok(false),
+            _ => {}
+        }
+        d = if d > 0 { -(d + 2) } else { -d + 2 };
+    }
+
+    let p = BigUint::one();
+    let q = to_residue((1 - d) / 4, num);
+    let d = to_residue(d, num);
+
+    // num + 1 = odd_part * 2^shift
+    let num_plus_one = num + 1u32;
+    let shift = num_plus_one.trailing_zeros().unwrap_or(0);
+    let odd_part = &num_plus_one >> shift;
+
+    // Walk the bits of odd_part from the top, tracking U_k, V_k and Q^k
+    let mut u = BigUint::one();
+    let mut v = p.clone();
+    let mut q_k = q.clone();
+    for bit in (0..odd_part.bits() - 1).rev() {
+        budget.check()?;
+        u = (&u * &v) % num;
+        v = sub_mod(&(&v * &v), &(&q_k << 1), num);
+        q_k = (&q_k * &q_k) % num;
+
+        if odd_part.bit(bit) {
+            let next_u = half_mod(&(&p * &u + &v), num);
+            let next_v = half_mod(&(&d * &u + &p * &v), num);
+            u = next_u;
+            v = next_v;
+            q_k = (&q_k * &q) % num;
+        }
+    }
+
+    if u.is_zero() || v.is_zero() {
+        return Ok(true);
+    }
+
+    for _ in 1..shift {
+        budget.check()?;
+        v = sub_mod(&(&v * &v), &(&q_k << 1), num);
+        if v.is_zero() {
+            return Ok(true);
+        }
+        q_k = (&q_k * &q_k) % num;
+    }
+
+    Ok(false)
+}
+
+fn jacobi(a: i64, n: &BigUint) -> i8 {
+    let mut a = to_residue(a, n);
+    let mut n = n.clone();
+    let mut result = 1;
+
+    while !a.is_zero() {
+        while a.is_even() {
+            a >>= 1;
+            let n_mod_8 = (&n % 8u32).to_u32().unwrap_or(0);
+            if n_mod_8 == 3 || n_mod_8 == 5 {
+                result = -result;
+            }
+        }
+
+        std::mem::swap(&mut a, &mut n);
+        if (&a % 4u32) == BigUint::from(3u32) && (&n % 4u32) == BigUint::from(3u32) {
+            result = -result;
+        }
+        a %= &n;
+    }
+
+    if n.is_one() {
+        result
+    } else {
+        0
+    }
+}
+
+// Modular arithmetic helpers for an odd modulus
+
+fn to_residue(value: i64, modulus: &BigUint) -> BigUint {
+    let residue = BigUint::from(value.unsigned_abs()) % modulus;
+    if value < 0 && !residue.is_zero() {
+        modulus - residue
+    } else {
+        residue
+    }
+}
+
+fn sub_mod(a: &BigUint, b: &BigUint, modulus: &BigUint) -> BigUint {
+    let a = a % modulus;
+    let b = b % modulus;
+    if a >= b {
+        a - b
+    } else {
+        modulus - b + a
+    }
+}
+
+fn half_mod(value: &BigUint, modulus: &BigUint) -> BigUint {
+    let value = value % modulus;
+    if value.is_even() {
+        value >> 1
+    } else {
+        (value + modulus) >> 1
+    }
+}
+
+/// Smallest prime strictly greater than num.
+pub fn next_prime(num: &BigInt, budget: &Budget) -> Result<BigInt, Timeout> {
+    let two = BigInt::from(2);
+    if *num < two {
+        return Ok(two);
+    }
+
+    // Step through odd candidates only
+    let mut candidate: BigInt = num + 1;
+    if candidate.is_even() {
+        candidate += 1;
+    }
+    while !is_prime_integer(&candidate, budget)? {
+        budget.check()?;
+        candidate += 2;
+    }
+
+    Ok(candidate)
+}
+
+/// Largest prime strictly less than num, if there is one.
+pub fn prev_prime(num: &BigInt, budget: &Budget) -> Result<Option<BigInt>, Timeout> {
+    let three = BigInt::from(3);
+    if *num <= BigInt::from(2) {
+        return Ok(None);
+    }
+    if *num == three {
+        return Ok(Some(BigInt::from(2)));
+    }
+
+    // Step through odd candidates only, stopping at 3 at the latest
+    let mut candidate: BigInt = num - 1;
+    if candidate.is_even() {
+        candidate -= 1;
+    }
+    while !is_prime_integer(&candidate, budget)? {
+        budget.check()?;
+        candidate -= 2;
+    }
+
+    Ok(Some(candidate))
+}
+
+/// Classic Miller-Rabin with `rounds` random bases. Composites slip through
+/// with probability at most 4^-rounds.
+pub fn is_probable_prime(num: &BigInt, rounds: u32, budget: &Budget) -> Result<bool, Timeout> {
+    if num.sign() == Sign::Minus {
+        return Ok(false);
+    }
+
+    let num = num.magnitude();
+    if *num <= BigUint::from(100u32) {
+        return Ok(num.to_u64().is_some_and(is_prime));
+    }
+
+    for prime in SMALL_PRIMES {
+        if (num % prime).is_zero() {
+            return Ok(false);
+        }
+    }
+
+    // Bases are drawn from [2, num - 2]
+    let mut rng = rand::thread_rng();
+    let low = BigUint::from(2u32);
+    let high = num - 1u32;
+    for _ in 0..rounds {
+        let base = rng.gen_biguint_range(&low, &high);
+        if !is_strong_probable_prime(num, &base, budget)? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // The trial division is_prime replaced, kept as the reference to check
+    // Miller-Rabin against
+    fn trial_division(num: u64) -> bool {
+        if num <= 1 {
+            return false;
+        }
+
+        let mut divisor = 2;
+        while divisor <= num / divisor {
+            if num.is_multiple_of(divisor) {
+                return false;
+            }
+            divisor += 1;
+        }
+
+        true
+    }
+
+    fn big(text: &str) -> BigUint {
+        text.parse().expect("valid integer")
+    }
+
+    fn mersenne(exponent: usize) -> BigUint {
+        (BigUint::one() << exponent) - 1u32
+    }
+
+    #[test]
+    fn baillie_psw_accepts_big_primes() {
+        let budget = Budget::new(None);
+        let primes = [
+            // The first prime past u64::MAX
+            big("18446744073709551629"),
+            big("340282366920938463463374607431768211507"),
+            mersenne(127),
+            mersenne(521),
+            mersenne(607),
+        ];
+
+        for prime in primes {
+            assert_eq!(is_prime_big(&prime, &budget), Ok(true), "{}", prime);
+        }
+    }
+
+    #[test]
+    fn baillie_psw_rejects_big_composites() {
+        let budget = Budget::new(None);
+        let composites = [
+            // 2^64 + 1 = 274177 * 67280421310721
+            big("18446744073709551617"),
+            // A strong pseudoprime to every base up to 37
+            big("318665857834031151167461"),
+            // A Carmichael number, 1454377 * 2908753 * 4363129
+            big("18457883288813385649"),
+            // The square of the first prime past u64::MAX
+            big("340282366920938463942989953348216553641"),
+            mersenne(127) * mersenne(89),
+            mersenne(128),
+        ];
+
+        for composite in composites {
+            assert_eq!(
+                is_prime_big(&composite, &budget),
+                Ok(false),
+                "{}",
+                composite
+            );
+        }
+    }
+
+    #[test]
+    fn miller_rabin_matches_trial_division_on_edge_cases() {
+        let mut cases: Vec<u64> = (0..10_000).collect();
+        cases.extend([
+            // Around the end of the small prime table and its square
+            97 * 97,
+            101 * 101 - 2,
+            101 * 101,
+            101 * 103,
+            // Carmichael numbers
+            561,
+            41041,
+            825265,
+            // Strong pseudoprimes to the first few bases
+            2047,
+            1373653,
+            25326001,
+            3215031751,
+            2152302898747,
+            3474749660383,
+            341550071728321,
+            3825123056546413051,
+            // Squares of primes and products of near-equal primes
+            65521 * 65521,
+            65519 * 65521,
+            4294967291 * 3,
+            // Mersenne numbers, both prime and composite
+            (1 << 31) - 1,
+            (1 << 62) - 1,
+            (1 << 32) + 1,
+            u64::MAX,
+        ]);
+
+        for num in cases {
+            assert_eq!(is_prime(num), trial_division(num), "is_prime({})", num);
+        }
+    }
+
+    #[test]
+    fn miller_rabin_matches_trial_division_on_random_inputs() {
+        let mut rng = StdRng::seed_from_u64(0x5EED);
+
+        // Trial division has to reach the square root to confirm a prime, so
+        // random inputs stay fairly small
+        for _ in 0..20_000 {
+            let num = rng.gen_range(0..1 << 32);
+            assert_eq!(is_prime(num), trial_division(num), "is_prime({})", num);
+        }
+        for _ in 0..500 {
+            let num = rng.gen_range(1 << 32..1 << 40);
+            assert_eq!(is_prime(num), trial_division(num), "is_prime({})", num);
+        }
+
+        // Big composites with a smallest factor trial division finds quickly
+        for _ in 0..2_000 {
+            let small = rng.gen_range(2..1 << 20);
+            let large = rng.gen_range(2..u64::MAX / small);
+            let num = small * large;
+            assert_eq!(is_prime(num), trial_division(num), "is_prime({})", num);
+        }
+    }
+
+    // Known primes at the top of the range, where trial division is too slow
+    #[test]
+    fn miller_rabin_knows_the_largest_u64_primes() {
+        assert!(is_prime((1 << 61) - 1));
+        assert!(is_prime(18446744073709551557));
+        assert!(is_prime(18446744073709551533));
+        assert!(!is_prime(18446744073709551559));
+        assert!(is_prime(4294967291));
+        assert!(is_prime(4294967311));
+    }
+}
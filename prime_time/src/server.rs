@@ -0,0 +1,372 @@
+use crate::budget::Budget;
+use crate::cache::PrimeCache;
+use crate::config::{Protocol, ServerConfig};
+use crate::framing::{
+    decode_request, has_buffered_request, negotiate, read_request, write_empty_frame,
+    write_message, Framing, ReadOutcome,
+};
+use crate::jsonrpc::{
+    handle_rpc_line, parse_rpc_line, rejected_response, rpc_request_count, unreadable_line_response,
+};
+use crate::limits::{ComputePermit, Limits, Rejection};
+use crate::methods::{
+    handle_request, respond_success, timeout_response, MethodResponse, RequestError,
+};
+use crate::pool::WorkerPool;
+use core::panic;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    io::{self, BufReader, BufWriter, ErrorKind, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+};
+
+// The result of one request. It travels tagged with its position in the
+// connection and whether the writer should flush once it's out.
+enum Outcome {
+    // A primesInRange stream does its sieving as the writer pulls from it, so
+    // it carries the request's permit until it has been written out
+    Response(MethodResponse, Option<ComputePermit>),
+    Malformed(RequestError),
+    // The request ran out of budget, which doesn't end the connection
+    TimedOut,
+    // Turned away by the rate limit or the concurrency cap
+    Rejected(Rejection),
+    // A JSON-RPC reply, or None when the request was only notifications
+    Rpc(Option<Value>),
+    // A JSON-RPC reply after which the connection is closed
+    RpcFatal(Value),
+}
+
+/// Accepts connections on the line protocol listener forever, evaluating
+/// requests on a shared pool of workers.
+pub fn serve(listener: TcpListener, config: ServerConfig, cache: PrimeCache, limits: Limits) {
+    // Every connection evaluates its requests on the same pool of workers
+    let pool = WorkerPool::new(config.workers);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let config = config.clone();
+                let pool = pool.clone();
+                let cache = cache.clone();
+                let limits = limits.clone();
+                thread::spawn(move || handle_connection(stream, config, pool, cache, limits));
+            }
+            Err(err) => panic!("Failed while listening for incoming connections: {}", err),
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    config: ServerConfig,
+    pool: WorkerPool,
+    cache: PrimeCache,
+    limits: Limits,
+) {
+    // Identify the session by the peer address for logging purposes
+    let peer_addr = stream.peer_addr().ok();
+    let peer = match peer_addr {
+        Some(addr) => addr.to_string(),
+        None => "unknown-peer".to_owned(),
+    };
+
+    println!("{} - INFO - Opened a new session", peer);
+
+    // Stalled clients shouldn't be able to hold this thread forever
+    if let Err(err) = stream.set_read_timeout(config.read_timeout) {
+        println!("{} - ERROR - Failed to set read timeout: {}", peer, err);
+        return;
+    }
+    // Nor a client that stops reading the writer. The timeout belongs to the
+    // socket, so the writer's clone of the stream has it too.
+    if let Err(err) = stream.set_write_timeout(config.write_timeout) {
+        println!("{} - ERROR - Failed to set write timeout: {}", peer, err);
+        return;
+    }
+
+    // Use a BufReader to enable reading until newlines
+    let mut reader = match stream.try_clone() {
+        Ok(read_stream) => BufReader::new(read_stream),
+        Err(err) => {
+            println!("{} - ERROR - Failed to clone stream: {}", peer, err);
+            return;
+        }
+    };
+
+    // JSON-RPC is always JSON lines. Native clients either get the configured
+    // framing or pick one with their first byte.
+    let framing = match (config.protocol, config.framing) {
+        (Protocol::JsonRpc, _) => Framing::Json,
+        (Protocol::Native, Some(framing)) => framing,
+        (Protocol::Native, None) => match negotiate(&mut reader) {
+            Ok(framing) => framing,
+            Err(err) => {
+                println!("{} - ERROR - Failed to read from client: {}", peer, err);
+                return;
+            }
+        },
+    };
+    if framing != Framing::Json {
+        println!("{} - INFO - Using {:?} framing", peer, framing);
+    }
+
+    // Each request takes a permit before it's evaluated and the writer hands it
+    // back once the response is out, which bounds the requests in flight
+    let (permits, returned_permits) = mpsc::sync_channel::<()>(config.max_in_flight);
+
+    // Responses may finish out of order, so a dedicated writer puts them back
+    // in sequence before they hit the socket
+    let (outcomes, pending) = mpsc::channel::<(u64, Outcome, bool)>();
+    let writer = {
+        let stream = match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("{} - ERROR - Failed to clone stream: {}", peer, err);
+                return;
+            }
+        };
+        let peer = peer.clone();
+        let diagnostic_errors = config.diagnostic_errors;
+        thread::spawn(move || {
+            write_responses(
+                stream,
+                framing,
+                pending,
+                returned_permits,
+                diagnostic_errors,
+                peer,
+            )
+        })
+    };
+
+    for sequence in 0.. {
+        let line = match read_request(&mut reader, framing, config.max_line_bytes) {
+            Ok(ReadOutcome::Request(line)) => Ok(line),
+            Ok(ReadOutcome::TooLong) if framing == Framing::Json => {
+                Err(RequestError::LineTooLong(config.max_line_bytes))
+            }
+            Ok(ReadOutcome::TooLong) => Err(RequestError::FrameTooLong(config.max_line_bytes)),
+            Ok(ReadOutcome::Eof) => {
+                println!("{} - INFO - Session closed by client", peer);
+                break;
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                println!("{} - INFO - Timed out waiting for client", peer);
+                break;
+            }
+            Err(err) => {
+                println!("{} - ERROR - Failed to read from client: {}", peer, err);
+                break;
+            }
+        };
+
+        // Once the reader has to wait on the client for more, the responses
+        // so far are a complete burst and go out together. A pipelining
+        // client costs a write per burst rather than one per response.
+        let flush = !config.batch_responses || !has_buffered_request(&reader, framing);
+
+        // This blocks while the connection is at its in-flight limit, and
+        // fails once the writer has given up on the connection
+        if permits.send(()).is_err() {
+            break;
+        }
+
+        // JSON-RPC lines are parsed before they're admitted, so that a batch
+        // is charged for every request it holds. A line that can't be
+        // evaluated at all is answered right away.
+        let rpc_request = match (config.protocol, &line) {
+            (Protocol::JsonRpc, Ok(line)) => match parse_rpc_line(line, &config) {
+                Ok(request) => Some(request),
+                Err(reply) => {
+                    let _ = outcomes.send((sequence, Outcome::Rpc(Some(reply)), flush));
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        let requests = rpc_request.as_ref().map_or(1, rpc_request_count);
+
+        // Over-limit requests are answered right away instead of evaluated.
+        // The permit counts against the global cap until the worker is done, or
+        // until a stream it produced has been written out.
+        let compute = match line {
+            Ok(_) => match limits.admit_batch(peer_addr.map(|addr| addr.ip()), requests) {
+                Ok(compute) => Some(compute),
+                Err(rejection) => {
+                    println!("{} - INFO - Rejected request: {}", peer, rejection);
+                    let outcome = match config.protocol {
+                        Protocol::JsonRpc => Outcome::Rpc(Some(rejected_response(&rejection))),
+                        Protocol::Native => Outcome::Rejected(rejection),
+                    };
+                    let _ = outcomes.send((sequence, outcome, flush));
+                    continue;
+                }
+            },
+            Err(_) => None,
+        };
+
+        let outcomes = outcomes.clone();
+        let cache = cache.clone();
+        let request_config = config.clone();
+
+        match (config.protocol, line, rpc_request) {
+            // JSON-RPC errors are ordinary responses, so the whole line is
+            // handed to a worker and the connection stays open
+            (Protocol::JsonRpc, Ok(_), Some(request)) => pool.execute(move || {
+                let reply = handle_rpc_line(request, &request_config, &cache);
+                drop(compute);
+                // The writer may already be gone if the client disconnected
+                let _ = outcomes.send((sequence, Outcome::Rpc(reply), flush));
+            }),
+            (Protocol::JsonRpc, Ok(_), None) => {
+                unreachable!("JSON-RPC lines are parsed before they're admitted")
+            }
+            (Protocol::JsonRpc, Err(err), _) => {
+                println!("{} - ERROR - Unreadable request: {}", peer, err);
+                let reply = unreadable_line_response(&err);
+                let _ = outcomes.send((sequence, Outcome::RpcFatal(reply), flush));
+                // We can't find the start of the next request, so give up
+                break;
+            }
+            (Protocol::Native, line, _) => {
+                let request = line.and_then(|line| decode_request(framing, line, &config));
+
+                match request {
+                    Ok(request) => pool.execute(move || {
+                        // The budget starts once a worker picks the request up
+                        let budget = Budget::new(request_config.request_budget);
+                        let outcome = match handle_request(request, &cache, budget) {
+                            Ok(response) => {
+                                let streaming =
+                                    matches!(response, MethodResponse::PrimesInRange(_));
+                                Outcome::Response(response, compute.filter(|_| streaming))
+                            }
+                            Err(_) => {
+                                drop(compute);
+                                Outcome::TimedOut
+                            }
+                        };
+                        // The writer may already be gone if the client disconnected
+                        let _ = outcomes.send((sequence, outcome, flush));
+                    }),
+                    Err(err) => {
+                        println!("{} - ERROR - Malformed request: {}", peer, err);
+                        let _ = outcomes.send((sequence, Outcome::Malformed(err), flush));
+                        // Break so we terminate the connection
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // The writer finishes once every outstanding response has been written
+    drop(outcomes);
+    let _ = writer.join();
+
+    let stats = cache.stats();
+    println!(
+        "{} - INFO - Cache stats: sieve_hits={} hits={} misses={}",
+        peer, stats.sieve_hits, stats.hits, stats.misses
+    );
+
+    println!("{} - INFO - Terminating session...", peer);
+}
+
+// Writes responses strictly in request order, however they arrive
+fn write_responses(
+    stream: TcpStream,
+    framing: Framing,
+    pending: mpsc::Receiver<(u64, Outcome, bool)>,
+    returned_permits: mpsc::Receiver<()>,
+    diagnostic_errors: bool,
+    peer: String,
+) {
+    let mut next_sequence = 0;
+    let mut out_of_order = BTreeMap::new();
+
+    // Responses are buffered up and only flushed at the end of each burst the
+    // reader picked up, or when the buffer fills
+    let mut writer = BufWriter::new(&stream);
+
+    while let Ok((sequence, outcome, flush)) = pending.recv() {
+        out_of_order.insert(sequence, (outcome, flush));
+
+        while let Some((outcome, flush)) = out_of_order.remove(&next_sequence) {
+            next_sequence += 1;
+
+            let write_result = match outcome {
+                Outcome::Response(response, _compute) => {
+                    respond_success(&mut writer, framing, response)
+                }
+                Outcome::TimedOut => {
+                    println!("{} - INFO - Request exceeded its time budget", peer);
+                    write_message(&mut writer, framing, &timeout_response())
+                }
+                Outcome::Rejected(rejection) => {
+                    write_message(&mut writer, framing, &rejection.to_response())
+                }
+                Outcome::Rpc(Some(reply)) => write_message(&mut writer, framing, &reply),
+                Outcome::Rpc(None) => Ok(()),
+                Outcome::RpcFatal(reply) => {
+                    let write_result =
+                        write_message(&mut writer, framing, &reply).and_then(|_| writer.flush());
+                    if let Err(err) = write_result {
+                        println!("{} - ERROR - Failed to write to client: {}", peer, err);
+                    }
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return;
+                }
+                Outcome::Malformed(request_error) => {
+                    let write_result = match (diagnostic_errors, framing) {
+                        (true, _) => {
+                            write_message(&mut writer, framing, &request_error.to_response())
+                        }
+                        (false, Framing::Json) => respond_failure(&mut writer),
+                        (false, _) => write_empty_frame(&mut writer),
+                    }
+                    .and_then(|_| writer.flush());
+                    if let Err(err) = write_result {
+                        println!("{} - ERROR - Failed to write to client: {}", peer, err);
+                    }
+                    // Nothing after a malformed request gets a response
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return;
+                }
+            };
+
+            let write_result = match write_result {
+                Ok(()) if flush => writer.flush(),
+                write_result => write_result,
+            };
+            if let Err(err) = write_result {
+                // A client that stopped reading is treated like one that's gone
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+                    println!("{} - INFO - Timed out writing to client", peer);
+                } else {
+                    println!("{} - ERROR - Failed to write to client: {}", peer, err);
+                }
+                // Unblock the reader so the whole connection winds down
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+
+            let _ = returned_permits.try_recv();
+        }
+    }
+
+    if let Err(err) = writer.flush() {
+        println!("{} - ERROR - Failed to write to client: {}", peer, err);
+    }
+}
+
+// Send Response
+
+fn respond_failure<W: Write>(mut writer: W) -> io::Result<()> {
+    // Write back a malformed response
+    writer.write_all("\n".as_bytes())
+}
@@ -0,0 +1,162 @@
+use std::sync::OnceLock;
+
+// Numbers covered by each sieve segment. Small enough to stay in cache while
+// still amortizing the per-segment setup.
+const SEGMENT_SIZE: u64 = 1 << 16;
+
+// Largest range end we'll sieve up to. Base primes run up to its square root,
+// 2^24, which makes about a million of them.
+pub const MAX_SIEVE_END: u64 = 1 << 48;
+
+// The base primes for every possible range, sieved once on first use and
+// shared by every request. Each request uses the prefix it needs.
+static BASE_PRIMES: OnceLock<Vec<u32>> = OnceLock::new();
+
+fn base_primes(high: u64) -> &'static [u32] {
+    let primes = BASE_PRIMES.get_or_init(|| primes_up_to(MAX_SIEVE_END.isqrt() as u32));
+    let root = high.isqrt();
+    &primes[..primes.partition_point(|&prime| prime as u64 <= root)]
+}
+
+/// All primes up to and including limit, using a sieve of Eratosthenes with
+/// one bit per odd number.
+pub fn primes_up_to(limit: u32) -> Vec<u32> {
+    if limit < 2 {
+        return Vec::new();
+    }
+
+    // Bit i stands for the odd number 2i + 1, and is set once it's known to
+    // be composite
+    let odd_count = (limit as usize).div_ceil(2);
+    let mut composite = vec![0u64; odd_count.div_ceil(64)];
+    let mut primes = vec![2];
+    for index in 1..odd_count {
+        if composite[index / 64] & (1 << (index % 64)) != 0 {
+            continue;
+        }
+
+        let num = 2 * index + 1;
+        primes.push(num as u32);
+
+        // Odd multiples are 2 * num apart, which is num bits apart
+        for multiple in (num * num / 2..odd_count).step_by(num) {
+            composite[multiple / 64] |= 1 << (multiple % 64);
+        }
+    }
+
+    primes
+}
+
+/// Walks [low, high] one segment at a time, yielding the primes found in each
+/// segment. Segments without any primes are skipped.
+#[derive(Debug)]
+pub struct SegmentedSieve {
+    base_primes: &'static [u32],
+    next_low: u64,
+    high: u64,
+    finished: bool,
+}
+
+impl SegmentedSieve {
+    pub fn new(low: u64, high: u64) -> Self {
+        assert!(high <= MAX_SIEVE_END, "sieve range end is too large");
+
+        Self {
+            base_primes: base_primes(high),
+            next_low: low.max(2),
+            high,
+            finished: low.max(2) > high,
+        }
+    }
+
+    fn sieve_segment(&self, low: u64, high: u64) -> Vec<u64> {
+        let mut composite = vec![false; (high - low + 1) as usize];
+
+        for &prime in self.base_primes {
+            let prime = prime as u64;
+            if prime * prime > high {
+                break;
+            }
+
+            // Start at the first multiple inside the segment, but never at the
+            // prime itself
+            let first = (prime * prime).max(low.div_ceil(prime) * prime);
+            for multiple in (first..=high).step_by(prime as usize) {
+                composite[(multiple - low) as usize] = true;
+            }
+        }
+
+        composite
+            .iter()
+            .enumerate()
+            .filter(|(_, &is_composite)| !is_composite)
+            .map(|(offset, _)| low + offset as u64)
+            .collect()
+    }
+}
+
+impl Iterator for SegmentedSieve {
+    type Item = Vec<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            let low = self.next_low;
+            let high = low.saturating_add(SEGMENT_SIZE - 1).min(self.high);
+
+            if high == self.high {
+                self.finished = true;
+            } else {
+                self.next_low = high + 1;
+            }
+
+            let primes = self.sieve_segment(low, high);
+            if !primes.is_empty() {
+                return Some(primes);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primality::is_prime;
+
+    #[test]
+    fn primes_up_to_matches_is_prime() {
+        for limit in [0, 1, 2, 3, 4, 63, 64, 65, 127, 128, 129, 10_000] {
+            let expected: Vec<u32> = (0..=limit).filter(|&num| is_prime(num as u64)).collect();
+            assert_eq!(primes_up_to(limit), expected, "primes_up_to({})", limit);
+        }
+    }
+
+    #[test]
+    fn base_primes_reach_the_square_root_of_the_largest_range() {
+        let primes = base_primes(MAX_SIEVE_END);
+        let largest = *primes.last().expect("there are base primes") as u64;
+        assert!(largest * largest <= MAX_SIEVE_END);
+        assert_eq!(primes.len(), 1_077_871);
+
+        assert_eq!(base_primes(1), &[] as &[u32]);
+        assert_eq!(base_primes(48), &[2, 3, 5]);
+        assert_eq!(base_primes(49), &[2, 3, 5, 7]);
+    }
+
+    #[test]
+    fn segmented_sieve_matches_is_prime() {
+        let ranges = [
+            (0, 100),
+            (90, 90),
+            (200_000, 200_000 + 3 * SEGMENT_SIZE),
+            (MAX_SIEVE_END - 5_000, MAX_SIEVE_END),
+        ];
+
+        for (low, high) in ranges {
+            let sieved: Vec<u64> = SegmentedSieve::new(low, high).flatten().collect();
+            let expected: Vec<u64> = (low..=high).filter(|&num| is_prime(num)).collect();
+            assert_eq!(sieved, expected, "primes in [{}, {}]", low, high);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+// Shared by the integration tests: a real server on an ephemeral port.
+
+use prime_time::{serve, Limits, PrimeCache, ServerConfig};
+use std::{
+    io::BufReader,
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+/// Starts a server with the defaults, adjusted by configure, and returns its
+/// address.
+pub fn start_server(configure: impl FnOnce(&mut ServerConfig)) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind an ephemeral port");
+    let addr = listener.local_addr().expect("listener has an address");
+
+    let mut config = ServerConfig::new(addr.to_string());
+    configure(&mut config);
+    let cache = PrimeCache::new(config.sieve_bound, config.cache_capacity);
+    let limits = Limits::new(&config);
+    thread::spawn(move || serve(listener, config, cache, limits));
+
+    addr
+}
+
+/// Opens a client connection, as a writer and a buffered reader. Reads time
+/// out so a server that never answers fails the test instead of hanging it.
+pub fn connect(addr: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(addr).expect("connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .expect("set a read timeout");
+    let reader = BufReader::new(stream.try_clone().expect("clone the stream"));
+    (stream, reader)
+}
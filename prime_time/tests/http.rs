@@ -0,0 +1,138 @@
+use prime_time::{serve_http, Limits, PrimeCache, ServerConfig};
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+fn start_http_server(configure: impl FnOnce(&mut ServerConfig)) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind an ephemeral port");
+    let addr = listener.local_addr().expect("listener has an address");
+
+    let mut config = ServerConfig::new(addr.to_string());
+    configure(&mut config);
+    let cache = PrimeCache::new(config.sieve_bound, config.cache_capacity);
+    let limits = Limits::new(&config);
+    thread::spawn(move || serve_http(listener, config, cache, limits));
+
+    addr
+}
+
+// Sends one request and returns the status line, the headers in lower case,
+// and a reader positioned at the start of the body
+fn post(addr: SocketAddr, path: &str, body: &str) -> (String, Vec<String>, BufReader<TcpStream>) {
+    let mut stream = TcpStream::connect(addr).expect("connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status).unwrap();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        headers.push(header.to_ascii_lowercase());
+    }
+
+    (status.trim_end().to_owned(), headers, reader)
+}
+
+// Reassembles a chunked body. Returns it with whether it ended with the
+// terminating empty chunk.
+fn read_chunked(reader: &mut BufReader<TcpStream>) -> (Vec<u8>, bool) {
+    let mut body = Vec::new();
+    loop {
+        let mut size = String::new();
+        if reader.read_line(&mut size).unwrap() == 0 {
+            return (body, false);
+        }
+        let size = usize::from_str_radix(size.trim_end(), 16).expect("a chunk size");
+        if size == 0 {
+            let mut end = String::new();
+            reader.read_line(&mut end).unwrap();
+            assert_eq!(end, "\r\n");
+            return (body, true);
+        }
+
+        let mut chunk = vec![0; size + 2];
+        reader.read_exact(&mut chunk).unwrap();
+        assert!(chunk.ends_with(b"\r\n"));
+        body.extend(&chunk[..size]);
+    }
+}
+
+#[test]
+fn primes_in_range_streams_as_chunks() {
+    let addr = start_http_server(|_| {});
+    let (status, headers, mut reader) = post(
+        addr,
+        "/primesInRange",
+        r#"{"method":"primesInRange","from":0,"to":1000000}"#,
+    );
+
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(headers.contains(&"transfer-encoding: chunked".to_owned()));
+    assert!(!headers
+        .iter()
+        .any(|header| header.starts_with("content-length")));
+
+    let (body, complete) = read_chunked(&mut reader);
+    assert!(complete);
+    let lines: Vec<Value> = body
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+
+    let (done, chunks) = lines.split_last().unwrap();
+    assert_eq!(done, &json!({"done": true}));
+    let primes: usize = chunks
+        .iter()
+        .map(|chunk| chunk["primes"].as_array().unwrap().len())
+        .sum();
+    assert_eq!(primes, 78_498);
+}
+
+#[test]
+fn primes_in_range_out_of_budget_gets_an_error_status() {
+    let addr = start_http_server(|config| config.request_budget = Some(Duration::from_nanos(1)));
+    let (status, _, _) = post(
+        addr,
+        "/primesInRange",
+        r#"{"method":"primesInRange","from":0,"to":1000000}"#,
+    );
+    assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+}
+
+#[test]
+fn other_methods_have_a_content_length() {
+    let addr = start_http_server(|_| {});
+    let (status, headers, mut reader) =
+        post(addr, "/isPrime", r#"{"method":"isPrime","number":7}"#);
+
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let mut body = String::new();
+    reader.read_to_string(&mut body).unwrap();
+    assert!(headers.contains(&format!("content-length: {}", body.len())));
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap(),
+        json!({"method": "isPrime", "prime": true})
+    );
+}
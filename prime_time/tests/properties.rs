@@ -0,0 +1,304 @@
+// Property tests for how numbers are read. Every JSON spelling of a value
+// has to get the same answer as the value itself, so random values are
+// written out in random spellings and checked against an oracle that knows
+// the value exactly.
+
+mod common;
+
+use common::{connect, start_server};
+use prime_time::{
+    handle_request, parse_request, Budget, MethodResponse, PrimeCache, RequestError, ServerConfig,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+const CASES: usize = 5_000;
+
+const TWO_TO_53: u128 = 1 << 53;
+const U64_MAX: u128 = u64::MAX as u128;
+
+// Every prime within 256 of 2^53, as offsets from it, and within 512 either
+// side of u64::MAX, as offsets below it and above it. Computed independently
+// with sympy.isprime.
+const PRIMES_NEAR_2_53: [i128; 11] = [-231, -145, -111, 5, 41, 57, 75, 119, 177, 219, 251];
+const PRIMES_BELOW_U64_MAX: [u128; 13] =
+    [502, 452, 424, 362, 352, 322, 278, 256, 188, 178, 94, 82, 58];
+const PRIMES_ABOVE_U64_MAX: [u128; 10] = [14, 38, 52, 82, 94, 142, 308, 332, 394, 494];
+
+// Only ever asked about values from the generator's ranges
+fn oracle(value: u128) -> bool {
+    if value < 1 << 20 {
+        return value >= 2
+            && (2..)
+                .take_while(|d| d * d <= value)
+                .all(|d| !value.is_multiple_of(d));
+    }
+    if value.abs_diff(TWO_TO_53) <= 256 {
+        return PRIMES_NEAR_2_53.contains(&(value as i128 - TWO_TO_53 as i128));
+    }
+    if value <= U64_MAX {
+        assert!(U64_MAX - value <= 512, "no oracle for {}", value);
+        return PRIMES_BELOW_U64_MAX.contains(&(U64_MAX - value));
+    }
+    assert!(value - U64_MAX <= 512, "no oracle for {}", value);
+    PRIMES_ABOVE_U64_MAX.contains(&(value - U64_MAX))
+}
+
+fn random_value(rng: &mut StdRng) -> u128 {
+    match rng.gen_range(0..4) {
+        0 => rng.gen_range(0..1 << 20),
+        1 => TWO_TO_53 - 256 + rng.gen_range(0..=512),
+        2 => U64_MAX - rng.gen_range(0..=512),
+        _ => U64_MAX + rng.gen_range(1..=512),
+    }
+}
+
+// A JSON spelling of the whole number with these decimal digits
+fn integer_spelling(rng: &mut StdRng, digits: &str) -> String {
+    match rng.gen_range(0..6) {
+        0 => digits.to_string(),
+        1 => format!("{}.{}", digits, "0".repeat(rng.gen_range(1..4))),
+        2 => format!(
+            "{}{}",
+            digits,
+            ["e0", "E+0", "e-0", "E00"][rng.gen_range(0..4)]
+        ),
+        // The decimal point moved left, and the exponent moving it back
+        3 => {
+            let shift = rng.gen_range(1..=digits.len());
+            let (int_part, frac_part) = digits.split_at(digits.len() - shift);
+            let int_part = if int_part.is_empty() { "0" } else { int_part };
+            format!("{}.{}e{}", int_part, frac_part, shift)
+        }
+        // Zeros appended and a negative exponent taking them off again
+        4 => {
+            let zeros = rng.gen_range(1..20);
+            format!("{}{}e-{}", digits, "0".repeat(zeros), zeros)
+        }
+        // Trailing zeros folded into the exponent
+        _ => {
+            let trimmed = digits.trim_end_matches('0');
+            if trimmed.is_empty() {
+                return "0e5".to_string();
+            }
+            format!("{}E+{}", trimmed, digits.len() - trimmed.len())
+        }
+    }
+}
+
+// A JSON spelling of a number strictly between value and value + 1
+fn fraction_spelling(rng: &mut StdRng, digits: &str) -> String {
+    let fraction = rng.gen_range(1..1000u32);
+    match rng.gen_range(0..3) {
+        0 => format!("{}.{:03}", digits, fraction),
+        // JSON doesn't allow the leading zeros this would give zero
+        1 if digits != "0" => format!("{}{:03}e-3", digits, fraction),
+        _ => format!("{}.{:03}e0", digits, fraction),
+    }
+}
+
+fn is_prime(number: &str) -> Result<bool, RequestError> {
+    let line = format!(r#"{{"method":"isPrime","number":{}}}"#, number);
+    let config = ServerConfig::new("127.0.0.1:0".to_owned());
+    let call = parse_request(&line, &config)?;
+    match handle_request(call, &PrimeCache::new(0, 0), Budget::new(None)) {
+        Ok(MethodResponse::IsPrime(response)) => Ok(response.prime),
+        other => panic!("{} got {:?}", number, other),
+    }
+}
+
+fn is_probable_prime(number: &str) -> bool {
+    let line = format!(r#"{{"method":"isProbablePrime","number":{}}}"#, number);
+    let config = ServerConfig::new("127.0.0.1:0".to_owned());
+    let call = parse_request(&line, &config).expect("a valid request");
+    match handle_request(call, &PrimeCache::new(0, 0), Budget::new(None)) {
+        Ok(MethodResponse::IsProbablePrime(response)) => response.prime,
+        other => panic!("{} got {:?}", number, other),
+    }
+}
+
+#[test]
+fn every_spelling_of_a_number_gets_the_oracles_answer() {
+    let mut rng = StdRng::seed_from_u64(0x5EED);
+
+    for _ in 0..CASES {
+        let value = random_value(&mut rng);
+        let digits = value.to_string();
+        let negative = rng.gen_bool(0.2);
+        let (spelling, expected) = if rng.gen_bool(0.2) {
+            (fraction_spelling(&mut rng, &digits), false)
+        } else {
+            // Negative numbers are never prime
+            (
+                integer_spelling(&mut rng, &digits),
+                !negative && oracle(value),
+            )
+        };
+        let number = if negative {
+            format!("-{}", spelling)
+        } else {
+            spelling
+        };
+
+        assert_eq!(is_prime(&number).unwrap(), expected, "isPrime {}", number);
+        assert_eq!(
+            is_probable_prime(&number),
+            expected,
+            "isProbablePrime {}",
+            number
+        );
+    }
+}
+
+#[test]
+fn float_edge_cases_get_exact_answers() {
+    let cases = [
+        ("0.0", false),
+        ("-0.0", false),
+        ("-0", false),
+        ("-0e0", false),
+        ("1e308", false),
+        ("-1e308", false),
+        ("1.7976931348623157e308", false),
+        ("1e999", false),
+        // Subnormal, the smallest normal, and far below either
+        ("5e-324", false),
+        ("4.9406564584124654e-324", false),
+        ("2.2250738585072014e-308", false),
+        ("2.2250738585072011e-308", false),
+        ("1e-999", false),
+        ("2e0", true),
+        ("0.2e1", true),
+        // 2^53 + 1 and 2^53 + 5. An f64 can't tell either from its neighbours.
+        ("9007199254740993", false),
+        ("9007199254740993.0", false),
+        ("9007199254740997", true),
+        ("9.007199254740997e15", true),
+        ("9007199254740997.000000000000001", false),
+        // u64::MAX - 58, u64::MAX, and u64::MAX + 14
+        ("18446744073709551557", true),
+        ("1.8446744073709551557e19", true),
+        ("18446744073709551615", false),
+        ("18446744073709551629", true),
+        ("18446744073709551629.0", true),
+    ];
+
+    for (number, expected) in cases {
+        assert_eq!(is_prime(number).unwrap(), expected, "isPrime {}", number);
+    }
+}
+
+#[test]
+fn nan_and_infinity_are_not_json() {
+    for number in [
+        "NaN",
+        "-NaN",
+        "Infinity",
+        "-Infinity",
+        "inf",
+        "1e",
+        "--1",
+        "+1",
+    ] {
+        assert!(
+            matches!(is_prime(number), Err(RequestError::InvalidJson(_))),
+            "{} was accepted",
+            number
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct FloatRequest {
+    method: &'static str,
+    number: f64,
+}
+
+#[derive(Deserialize)]
+struct IsPrimeReply {
+    prime: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Binary {
+    MessagePack,
+    Cbor,
+}
+
+// Sends one isPrime request with a native float over a binary framing.
+// Returns the answer, or None for the empty frame of a malformed request.
+fn binary_is_prime(framing: Binary, number: f64) -> Option<bool> {
+    let request = FloatRequest {
+        method: "isPrime",
+        number,
+    };
+    let (preamble, payload) = match framing {
+        Binary::MessagePack => (0x01, rmp_serde::to_vec_named(&request).unwrap()),
+        Binary::Cbor => {
+            let mut payload = Vec::new();
+            ciborium::into_writer(&request, &mut payload).unwrap();
+            (0x02, payload)
+        }
+    };
+
+    let addr = start_server(|_| {});
+    let (mut stream, mut reader) = connect(addr);
+    stream.write_all(&[preamble]).unwrap();
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(&payload).unwrap();
+
+    let mut length = [0; 4];
+    reader.read_exact(&mut length).unwrap();
+    let mut reply = vec![0; u32::from_be_bytes(length) as usize];
+    reader.read_exact(&mut reply).unwrap();
+    if reply.is_empty() {
+        // A malformed request ends the session
+        assert_eq!(reader.read(&mut [0]).unwrap(), 0, "{} stayed open", number);
+        return None;
+    }
+
+    let reply: IsPrimeReply = match framing {
+        Binary::MessagePack => rmp_serde::from_slice(&reply).unwrap(),
+        Binary::Cbor => ciborium::from_reader(reply.as_slice()).unwrap(),
+    };
+    Some(reply.prime)
+}
+
+#[test]
+fn binary_floats_get_exact_answers() {
+    let cases = [
+        (f64::NAN, None),
+        (-f64::NAN, None),
+        (f64::INFINITY, None),
+        (f64::NEG_INFINITY, None),
+        (0.0, Some(false)),
+        (-0.0, Some(false)),
+        (1e308, Some(false)),
+        (f64::MAX, Some(false)),
+        (5e-324, Some(false)),
+        (f64::MIN_POSITIVE, Some(false)),
+        (7.0, Some(true)),
+        (7.5, Some(false)),
+        (-7.0, Some(false)),
+        // 2^53 - 111, the largest prime an f64 holds exactly, then 2^53
+        (9007199254740881.0, Some(true)),
+        (9007199254740992.0, Some(false)),
+        // 2^64, the nearest f64 to u64::MAX
+        (18446744073709551615.0, Some(false)),
+    ];
+
+    for framing in [Binary::MessagePack, Binary::Cbor] {
+        for (number, expected) in cases {
+            assert_eq!(
+                binary_is_prime(framing, number),
+                expected,
+                "{:?} {}",
+                framing,
+                number
+            );
+        }
+    }
+}
@@ -0,0 +1,346 @@
+mod common;
+
+use common::{connect, start_server};
+use num_bigint::BigInt;
+use prime_time::{verify_certificate, Budget, Certificate, Protocol};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+fn read_response<R: BufRead>(reader: &mut R) -> Value {
+    let mut line = String::new();
+    reader.read_line(&mut line).expect("read a response");
+    serde_json::from_str(&line).unwrap_or_else(|err| panic!("{:?} isn't JSON: {}", line, err))
+}
+
+#[test]
+fn answers_is_prime() {
+    let addr = start_server(|_| {});
+    let (mut stream, mut reader) = connect(addr);
+
+    stream
+        .write_all(
+            b"{\"method\":\"isPrime\",\"number\":123}\n{\"method\":\"isPrime\",\"number\":7}\n",
+        )
+        .unwrap();
+
+    assert_eq!(
+        read_response(&mut reader),
+        json!({"method": "isPrime", "prime": false})
+    );
+    assert_eq!(
+        read_response(&mut reader),
+        json!({"method": "isPrime", "prime": true})
+    );
+}
+
+#[test]
+fn malformed_request_gets_a_malformed_response_and_the_session_ends() {
+    let addr = start_server(|_| {});
+    let (mut stream, mut reader) = connect(addr);
+
+    stream
+        .write_all(
+            b"{\"method\":\"isPrime\",\"number\":\"7\"}\n{\"method\":\"isPrime\",\"number\":7}\n",
+        )
+        .unwrap();
+
+    let mut rest = Vec::new();
+    reader
+        .read_to_end(&mut rest)
+        .expect("the server closes the session");
+    assert_eq!(rest, b"\n");
+}
+
+#[test]
+fn pipelined_requests_are_answered_in_order() {
+    let addr = start_server(|_| {});
+    let (mut stream, mut reader) = connect(addr);
+
+    // A slow Mersenne prime first, so the quick answers behind it finish
+    // early and have to wait their turn
+    let mut numbers = vec!["170141183460469231731687303715884105727".to_string()];
+    numbers.extend((0..200u64).map(|i| (u64::MAX - i).to_string()));
+    numbers.extend((0..200u64).map(|i| i.to_string()));
+
+    let mut requests = String::new();
+    for number in &numbers {
+        requests.push_str(&format!(
+            "{{\"method\":\"isPrime\",\"number\":{}}}\n",
+            number
+        ));
+    }
+    stream.write_all(requests.as_bytes()).unwrap();
+
+    for number in &numbers {
+        let expected = match number.parse::<u64>() {
+            Ok(number) => prime_time::is_prime(number),
+            Err(_) => true,
+        };
+        assert_eq!(
+            read_response(&mut reader),
+            json!({"method": "isPrime", "prime": expected}),
+            "answer for {}",
+            number
+        );
+    }
+}
+
+#[test]
+fn range_stream_holds_its_permit_until_written_out() {
+    let addr = start_server(|config| {
+        config.max_active = 1;
+        config.max_range_width = u64::MAX;
+        config.request_budget = None;
+    });
+
+    // Far more primes than the socket buffers hold, and the client stops
+    // reading after the first line, so the stream stays unfinished
+    let (mut streaming, mut stream_reader) = connect(addr);
+    streaming
+        .write_all(b"{\"method\":\"primesInRange\",\"from\":0,\"to\":1000000000000}\n")
+        .unwrap();
+    assert_eq!(
+        read_response(&mut stream_reader)["method"],
+        json!("primesInRange")
+    );
+
+    let (mut stream, mut reader) = connect(addr);
+    let request = b"{\"method\":\"isPrime\",\"number\":7}\n";
+    stream.write_all(request).unwrap();
+    assert_eq!(
+        read_response(&mut reader)["error"]["kind"],
+        json!("overloaded")
+    );
+
+    // Hanging up abandons the stream, which gives the permit back
+    drop(stream_reader);
+    drop(streaming);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        stream.write_all(request).unwrap();
+        let response = read_response(&mut reader);
+        if response == json!({"method": "isPrime", "prime": true}) {
+            break;
+        }
+        assert_eq!(response["error"]["kind"], json!("overloaded"));
+        assert!(Instant::now() < deadline, "the permit was never returned");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+const MESSAGE_PACK: u8 = 0x01;
+const CBOR: u8 = 0x02;
+
+#[derive(Serialize)]
+struct NumberRequest<N> {
+    method: &'static str,
+    number: N,
+}
+
+#[derive(Deserialize)]
+struct NextPrimeReply {
+    number: String,
+}
+
+#[derive(Deserialize)]
+struct IsPrimeReply {
+    prime: bool,
+}
+
+// One request and its reply in the binary framing picked by preamble
+fn exchange<T: Serialize, R: DeserializeOwned>(
+    stream: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    preamble: u8,
+    request: &T,
+) -> R {
+    let payload = match preamble {
+        MESSAGE_PACK => rmp_serde::to_vec_named(request).unwrap(),
+        _ => {
+            let mut payload = Vec::new();
+            ciborium::into_writer(request, &mut payload).unwrap();
+            payload
+        }
+    };
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(&payload).unwrap();
+
+    let mut length = [0; 4];
+    reader.read_exact(&mut length).unwrap();
+    let mut reply = vec![0; u32::from_be_bytes(length) as usize];
+    reader.read_exact(&mut reply).unwrap();
+    match preamble {
+        MESSAGE_PACK => rmp_serde::from_slice(&reply).unwrap(),
+        _ => ciborium::from_reader(reply.as_slice()).unwrap(),
+    }
+}
+
+#[test]
+fn binary_clients_can_send_back_the_wide_integers_they_receive() {
+    let addr = start_server(|_| {});
+
+    for preamble in [MESSAGE_PACK, CBOR] {
+        let (mut stream, mut reader) = connect(addr);
+        stream.write_all(&[preamble]).unwrap();
+
+        // The first prime past u64::MAX only fits in a response as text
+        let next: NextPrimeReply = exchange(
+            &mut stream,
+            &mut reader,
+            preamble,
+            &NumberRequest {
+                method: "nextPrime",
+                number: u64::MAX,
+            },
+        );
+        assert_eq!(next.number, "18446744073709551629");
+
+        let is_prime: IsPrimeReply = exchange(
+            &mut stream,
+            &mut reader,
+            preamble,
+            &NumberRequest {
+                method: "isPrime",
+                number: next.number.as_str(),
+            },
+        );
+        assert!(is_prime.prime);
+
+        let is_prime: IsPrimeReply = exchange(
+            &mut stream,
+            &mut reader,
+            preamble,
+            &NumberRequest {
+                method: "isPrime",
+                number: "-18446744073709551629",
+            },
+        );
+        assert!(!is_prime.prime);
+    }
+}
+
+#[test]
+fn binary_numbers_must_be_plain_decimal_text() {
+    let addr = start_server(|_| {});
+
+    for number in ["1e3", "0x10", " 7", "+7", "7.0", ""] {
+        let (mut stream, mut reader) = connect(addr);
+        stream.write_all(&[MESSAGE_PACK]).unwrap();
+        let payload = rmp_serde::to_vec_named(&NumberRequest {
+            method: "isPrime",
+            number,
+        })
+        .unwrap();
+        stream
+            .write_all(&(payload.len() as u32).to_be_bytes())
+            .unwrap();
+        stream.write_all(&payload).unwrap();
+
+        // An empty frame, then the session ends
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, [0, 0, 0, 0], "{:?}", number);
+    }
+}
+
+#[test]
+fn certificates_over_tcp_verify() {
+    let addr = start_server(|_| {});
+    let (mut stream, mut reader) = connect(addr);
+
+    // A prime, a small composite, and 2^128 + 1, a composite past u64::MAX
+    for (number, prime) in [
+        ("1000000007", true),
+        ("1000000008", false),
+        ("340282366920938463463374607431768211457", false),
+    ] {
+        writeln!(
+            stream,
+            r#"{{"method":"isPrime","number":{},"certificate":true}}"#,
+            number
+        )
+        .unwrap();
+        let response = read_response(&mut reader);
+        assert_eq!(response["prime"], prime, "{}", number);
+
+        let certificate: Certificate = serde_json::from_value(response["certificate"].clone())
+            .unwrap_or_else(|err| panic!("{} has no certificate: {}", number, err));
+        assert_eq!(
+            verify_certificate(
+                &number.parse::<BigInt>().unwrap(),
+                &certificate,
+                &Budget::new(None)
+            ),
+            Ok(true),
+            "{}",
+            number
+        );
+    }
+}
+
+#[test]
+fn json_rpc_batches_are_charged_for_every_request() {
+    let addr = start_server(|config| {
+        config.protocol = Protocol::JsonRpc;
+        config.rate_limit = Some(1);
+        config.rate_burst = 5;
+    });
+    let (mut stream, mut reader) = connect(addr);
+    let batch = r#"[{"jsonrpc":"2.0","method":"isPrime","params":[7],"id":1},{"jsonrpc":"2.0","method":"isPrime","params":[7],"id":2},{"jsonrpc":"2.0","method":"isPrime","params":[7],"id":3},{"jsonrpc":"2.0","method":"isPrime","params":[7],"id":4}]"#;
+
+    // Four of the five tokens go on the first batch, so the second can't
+    // have another four
+    writeln!(stream, "{}", batch).unwrap();
+    assert_eq!(read_response(&mut reader).as_array().unwrap().len(), 4);
+    writeln!(stream, "{}", batch).unwrap();
+    assert_eq!(read_response(&mut reader)["error"]["code"], -32001);
+
+    // A lone request still fits in what's left
+    writeln!(
+        stream,
+        r#"{{"jsonrpc":"2.0","method":"isPrime","params":[7],"id":5}}"#
+    )
+    .unwrap();
+    assert_eq!(read_response(&mut reader)["result"]["prime"], true);
+}
+
+#[test]
+fn a_client_that_stops_reading_is_dropped() {
+    let addr = start_server(|config| {
+        config.max_active = 1;
+        config.max_range_width = u64::MAX;
+        config.request_budget = None;
+        config.write_timeout = Some(Duration::from_millis(200));
+    });
+
+    // The client never reads, so the writer fills the socket buffers and
+    // then waits on it while holding the only permit
+    let (mut streaming, _stream_reader) = connect(addr);
+    streaming
+        .write_all(b"{\"method\":\"primesInRange\",\"from\":0,\"to\":1000000000000}\n")
+        .unwrap();
+
+    // Once the write times out the connection is dropped and the permit
+    // comes back, even though the client is still connected
+    let (mut stream, mut reader) = connect(addr);
+    let request = b"{\"method\":\"isPrime\",\"number\":7}\n";
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        stream.write_all(request).unwrap();
+        let response = read_response(&mut reader);
+        if response == json!({"method": "isPrime", "prime": true}) {
+            break;
+        }
+        assert_eq!(response["error"]["kind"], json!("overloaded"));
+        assert!(Instant::now() < deadline, "the permit was never returned");
+        thread::sleep(Duration::from_millis(20));
+    }
+}